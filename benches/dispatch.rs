@@ -0,0 +1,129 @@
+//! Throughput of many tiny jobs through `ThreadPool`, against the single
+//! `Mutex<mpsc::Receiver>` queue the pool started out with.
+//!
+//! The baseline does nothing but hand out jobs: no timings, stats or panic
+//! catching. The interesting part is how each holds up as workers and
+//! producers are added, not the absolute numbers.
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rs_webserver::ThreadPool;
+
+const JOBS: usize = 400_000;
+const ROUNDS: usize = 5;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The old design: every worker takes jobs off one shared receiver.
+struct MutexPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl MutexPool {
+    fn new(size: usize) -> MutexPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        MutexPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for MutexPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+/// Submits `JOBS` jobs split across `producers` threads and waits for all of
+/// them to run. Returns the best of a few rounds.
+fn run<P, E>(pool: &Arc<P>, producers: usize, execute: E) -> Duration
+where
+    P: Send + Sync + 'static,
+    E: Fn(&P, Box<dyn FnOnce() + Send>) + Send + Sync + Copy + 'static,
+{
+    (0..ROUNDS)
+        .map(|_| {
+            let done = Arc::new(AtomicUsize::new(0));
+            let started = Instant::now();
+            let threads: Vec<_> = (0..producers)
+                .map(|_| {
+                    let pool = pool.clone();
+                    let done = done.clone();
+                    thread::spawn(move || {
+                        for _ in 0..JOBS / producers {
+                            let done = done.clone();
+                            execute(
+                                &pool,
+                                Box::new(move || {
+                                    done.fetch_add(1, Ordering::Relaxed);
+                                }),
+                            );
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+            while done.load(Ordering::Relaxed) < JOBS / producers * producers {
+                thread::yield_now();
+            }
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn rate(elapsed: Duration) -> String {
+    format!("{:.2}M/s", JOBS as f64 / elapsed.as_secs_f64() / 1e6)
+}
+
+fn main() {
+    println!("{} tiny jobs, best of {}", JOBS, ROUNDS);
+    println!(
+        "{:>8} {:>10} {:>14} {:>14}",
+        "workers", "producers", "mutex queue", "ThreadPool"
+    );
+    for &workers in &[1, 2, 4, 8, 16] {
+        for &producers in &[1, 4] {
+            let old = Arc::new(MutexPool::new(workers));
+            let old = run(&old, producers, |pool, job| pool.execute(job));
+            let new = Arc::new(ThreadPool::new(workers));
+            let new = run(&new, producers, |pool, job| pool.execute(job).unwrap());
+            println!(
+                "{:>8} {:>10} {:>14} {:>14}",
+                workers,
+                producers,
+                rate(old),
+                rate(new)
+            );
+        }
+    }
+}
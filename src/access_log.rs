@@ -0,0 +1,581 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::date::{self, DateTime};
+use crate::request::Request;
+use crate::response::StatusCode;
+
+/// How access log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Apache's Common Log Format.
+    Common,
+    /// Apache's Combined Log Format, with the latency in microseconds
+    /// appended like `%D`.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AccessLogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err("expected one of common, combined, json".to_string()),
+        }
+    }
+}
+
+/// When the log file is moved aside and a fresh one started.
+///
+/// Rotated files get a suffix: the period they cover for time based
+/// rotation (`access.log.2026-10-17`), the time of rotation for size based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    /// Once the file would grow past this many bytes.
+    Size(u64),
+    Hourly,
+    Daily,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    /// Parses `never`, `hourly`, `daily` or a size such as `50M`.
+    fn from_str(s: &str) -> Result<Rotation, String> {
+        let lower = s.to_ascii_lowercase();
+        match lower.as_str() {
+            "never" => return Ok(Rotation::Never),
+            "hourly" => return Ok(Rotation::Hourly),
+            "daily" => return Ok(Rotation::Daily),
+            _ => {}
+        }
+        let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => (&lower[..i], &lower[i..]),
+            None => (lower.as_str(), ""),
+        };
+        let multiplier = match unit {
+            "" | "b" => 1,
+            "k" => 1 << 10,
+            "m" => 1 << 20,
+            "g" => 1 << 30,
+            _ => 0,
+        };
+        match digits.parse::<u64>() {
+            Ok(n) if n > 0 && multiplier > 0 => Ok(Rotation::Size(n * multiplier)),
+            _ => Err("expected never, hourly, daily or a size like 100M".to_string()),
+        }
+    }
+}
+
+/// What gets logged about one request.
+pub(crate) struct Entry<'a> {
+    pub peer: Option<SocketAddr>,
+    pub time: SystemTime,
+    /// `None` for a request turned away before it was fully read.
+    pub request: Option<&'a Request>,
+    /// As much of the request line as was read, for those.
+    pub request_line: Option<&'a str>,
+    pub status: StatusCode,
+    /// Body bytes sent, not counting headers or chunk framing.
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// A per-request access log, written to stdout or a file.
+///
+/// Shared between connections; each line is written with a single write.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Mutex<Sink>,
+}
+
+enum Sink {
+    Stdout,
+    File(LogFile),
+}
+
+impl AccessLog {
+    pub fn stdout(format: AccessLogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            sink: Mutex::new(Sink::Stdout),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        format: AccessLogFormat,
+        rotation: Rotation,
+    ) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            format,
+            sink: Mutex::new(Sink::File(LogFile::open(path.as_ref(), rotation)?)),
+        })
+    }
+
+    pub fn format(&self) -> AccessLogFormat {
+        self.format
+    }
+
+    pub(crate) fn log(&self, entry: &Entry) {
+        let mut line = match self.format {
+            AccessLogFormat::Common => common(entry),
+            AccessLogFormat::Combined => combined(entry),
+            AccessLogFormat::Json => json(entry),
+        };
+        line.push('\n');
+        let result = match &mut *self.lock() {
+            Sink::Stdout => io::stdout().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(line.as_bytes(), entry.time),
+        };
+        if let Err(e) = result {
+            crate::warn!("couldn't write to the access log: {}", e);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Sink> {
+        self.sink.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: Rotation,
+    // the hour or day the current file covers, for time based rotation
+    period: String,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        // carry on with an existing file's period rather than today's, so a
+        // restart doesn't mix days
+        let since = if meta.len() > 0 {
+            meta.modified().unwrap_or_else(|_| SystemTime::now())
+        } else {
+            SystemTime::now()
+        };
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file,
+            size: meta.len(),
+            rotation,
+            period: period(rotation, since),
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8], now: SystemTime) -> io::Result<()> {
+        let rotate_to = match self.rotation {
+            Rotation::Never => None,
+            Rotation::Size(max) if self.size > 0 && self.size + line.len() as u64 > max => {
+                Some(period(Rotation::Size(max), now))
+            }
+            Rotation::Size(_) => None,
+            Rotation::Hourly | Rotation::Daily => {
+                let current = period(self.rotation, now);
+                if current != self.period {
+                    Some(std::mem::replace(&mut self.period, current))
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(suffix) = rotate_to {
+            self.rotate(&suffix)?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self, suffix: &str) -> io::Result<()> {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".");
+        name.push(suffix);
+        let mut target = PathBuf::from(&name);
+        let mut n = 1;
+        while target.exists() {
+            let mut numbered = name.clone();
+            numbered.push(format!(".{}", n));
+            target = PathBuf::from(numbered);
+            n += 1;
+        }
+        fs::rename(&self.path, &target)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn period(rotation: Rotation, time: SystemTime) -> String {
+    let t = DateTime::from_system_time(time);
+    match rotation {
+        Rotation::Daily => format!("{}-{:02}-{:02}", t.year, t.month, t.day),
+        Rotation::Hourly => format!("{}-{:02}-{:02}T{:02}", t.year, t.month, t.day, t.hour),
+        Rotation::Never | Rotation::Size(_) => format!(
+            "{}-{:02}-{:02}T{:02}-{:02}-{:02}",
+            t.year, t.month, t.day, t.hour, t.minute, t.second
+        ),
+    }
+}
+
+fn common(entry: &Entry) -> String {
+    let line = match (entry.request, entry.request_line) {
+        (Some(request), _) => format!(
+            "{} {} {}",
+            escape(request.method().as_str()),
+            escape(request.target()),
+            request.version().as_str()
+        ),
+        (None, Some(line)) => escape(line),
+        (None, None) => "-".to_string(),
+    };
+    let host = match entry.peer {
+        Some(peer) => peer.ip().to_string(),
+        None => "-".to_string(),
+    };
+    let bytes = if entry.bytes == 0 {
+        "-".to_string()
+    } else {
+        entry.bytes.to_string()
+    };
+    format!(
+        "{} - - [{}] \"{}\" {} {}",
+        host,
+        date::log_date(entry.time),
+        line,
+        entry.status.as_u16(),
+        bytes
+    )
+}
+
+fn combined(entry: &Entry) -> String {
+    let header = |name| match entry.request.and_then(|r| r.header(name)) {
+        Some(value) => escape(value),
+        None => "-".to_string(),
+    };
+    format!(
+        "{} \"{}\" \"{}\" {}",
+        common(entry),
+        header("Referer"),
+        header("User-Agent"),
+        entry.duration.as_micros()
+    )
+}
+
+fn json(entry: &Entry) -> String {
+    let request = entry.request;
+    let string = |value: Option<&str>| match value {
+        Some(value) => json_string(value),
+        None => "null".to_string(),
+    };
+    let remote = entry.peer.map(|peer| peer.ip().to_string());
+    let mut line = format!(
+        "{{\"time\":\"{}\",\"remote\":{},\"method\":{},\"path\":{},\"query\":{},\
+         \"protocol\":{},\"status\":{},\"bytes\":{},\"referer\":{},\
+         \"user_agent\":{},\"duration_us\":{}",
+        date::rfc3339(entry.time),
+        string(remote.as_deref()),
+        string(request.map(|r| r.method().as_str())),
+        string(request.map(|r| r.path())),
+        string(request.and_then(|r| r.query())),
+        string(request.map(|r| r.version().as_str())),
+        entry.status.as_u16(),
+        entry.bytes,
+        string(request.and_then(|r| r.header("Referer"))),
+        string(request.and_then(|r| r.header("User-Agent"))),
+        entry.duration.as_micros()
+    );
+    // the raw line is all there is for a request that didn't parse
+    if let (None, Some(raw)) = (request, entry.request_line) {
+        let _ = write!(line, ",\"request_line\":{}", json_string(raw));
+    }
+    line.push('}');
+    line
+}
+
+// Quotes, backslashes and anything unprintable are escaped like Apache does,
+// so a client can't forge log lines.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\x{:02x}", b);
+            }
+        }
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Parser;
+    use std::env;
+    use std::process;
+    use std::time::UNIX_EPOCH;
+
+    // 2001-09-09T01:46:40Z
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+    }
+
+    fn request(text: &str) -> Request {
+        let mut parser = Parser::new();
+        parser.feed(text.as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn entry<'a>(request: Option<&'a Request>, request_line: Option<&'a str>) -> Entry<'a> {
+        Entry {
+            peer: Some("192.0.2.7:51000".parse().unwrap()),
+            time: time(),
+            request,
+            request_line,
+            status: StatusCode::Ok,
+            bytes: 512,
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    fn browser_request() -> Request {
+        request(
+            "GET /docs/page.html?lang=en HTTP/1.1\r\nHost: example.com\r\n\
+             Referer: http://example.com/\r\nUser-Agent: Test/1.0\r\n\r\n",
+        )
+    }
+
+    #[test]
+    fn common_format() {
+        let request = browser_request();
+        assert_eq!(
+            common(&entry(Some(&request), None)),
+            "192.0.2.7 - - [09/Sep/2001:01:46:40 +0000] \
+             \"GET /docs/page.html?lang=en HTTP/1.1\" 200 512"
+        );
+
+        let mut rejected = entry(None, Some("BREW /pot HTTP/1.1"));
+        rejected.status = StatusCode::BadRequest;
+        rejected.bytes = 0;
+        rejected.peer = None;
+        assert_eq!(
+            common(&rejected),
+            "- - - [09/Sep/2001:01:46:40 +0000] \"BREW /pot HTTP/1.1\" 400 -"
+        );
+        assert!(common(&entry(None, None)).contains(" \"-\" 200 "));
+    }
+
+    #[test]
+    fn combined_format() {
+        let browser = browser_request();
+        assert_eq!(
+            combined(&entry(Some(&browser), None)),
+            "192.0.2.7 - - [09/Sep/2001:01:46:40 +0000] \
+             \"GET /docs/page.html?lang=en HTTP/1.1\" 200 512 \
+             \"http://example.com/\" \"Test/1.0\" 1500"
+        );
+        let bare = request("GET / HTTP/1.0\r\n\r\n");
+        assert!(combined(&entry(Some(&bare), None)).ends_with(" \"-\" \"-\" 1500"));
+    }
+
+    #[test]
+    fn json_format() {
+        let request = browser_request();
+        assert_eq!(
+            json(&entry(Some(&request), None)),
+            "{\"time\":\"2001-09-09T01:46:40Z\",\"remote\":\"192.0.2.7\",\
+             \"method\":\"GET\",\"path\":\"/docs/page.html\",\"query\":\"lang=en\",\
+             \"protocol\":\"HTTP/1.1\",\"status\":200,\"bytes\":512,\
+             \"referer\":\"http://example.com/\",\"user_agent\":\"Test/1.0\",\
+             \"duration_us\":1500}"
+        );
+        assert_eq!(
+            json(&entry(None, Some("BREW /pot"))),
+            "{\"time\":\"2001-09-09T01:46:40Z\",\"remote\":\"192.0.2.7\",\
+             \"method\":null,\"path\":null,\"query\":null,\"protocol\":null,\
+             \"status\":200,\"bytes\":512,\"referer\":null,\"user_agent\":null,\
+             \"duration_us\":1500,\"request_line\":\"BREW /pot\"}"
+        );
+    }
+
+    #[test]
+    fn request_fields_are_escaped() {
+        let sneaky = request(
+            "GET /a\"b\\c HTTP/1.1\r\nHost: x\r\n\
+             User-Agent: evil\" 200 0 \"\x01\tagent \u{e9}\r\n\r\n",
+        );
+        let line = combined(&entry(Some(&sneaky), None));
+        assert!(line.contains("\"GET /a\\\"b\\\\c HTTP/1.1\""), "{}", line);
+        assert!(
+            line.ends_with(" \"evil\\\" 200 0 \\\"\\x01\\x09agent \\xc3\\xa9\" 1500"),
+            "{}",
+            line
+        );
+
+        let line = json(&entry(Some(&sneaky), None));
+        assert!(line.contains("\"path\":\"/a\\\"b\\\\c\""), "{}", line);
+        assert!(
+            line.contains("\"user_agent\":\"evil\\\" 200 0 \\\"\\u0001\\tagent \u{e9}\""),
+            "{}",
+            line
+        );
+
+        // what the client sent before being turned away is theirs too
+        let line = common(&entry(None, Some("GET /\x1b[2J \"x\"")));
+        assert!(line.contains("\"GET /\\x1b[2J \\\"x\\\"\""), "{}", line);
+        let line = json(&entry(None, Some("a\nb")));
+        assert!(line.ends_with(",\"request_line\":\"a\\nb\"}"), "{}", line);
+    }
+
+    #[test]
+    fn parses_rotation() {
+        assert_eq!("never".parse(), Ok(Rotation::Never));
+        assert_eq!("Daily".parse(), Ok(Rotation::Daily));
+        assert_eq!("hourly".parse(), Ok(Rotation::Hourly));
+        assert_eq!("4096".parse(), Ok(Rotation::Size(4096)));
+        assert_eq!("10k".parse(), Ok(Rotation::Size(10 << 10)));
+        assert_eq!("100M".parse(), Ok(Rotation::Size(100 << 20)));
+        assert_eq!("2g".parse(), Ok(Rotation::Size(2 << 30)));
+        for bad in ["", "0", "10x", "weekly", "M"] {
+            assert!(bad.parse::<Rotation>().is_err(), "{:?}", bad);
+        }
+    }
+
+    // a scratch directory for log files, removed again on drop
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let dir = env::temp_dir().join(format!("rs-webserver-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Dir(dir)
+        }
+
+        // file name and contents, sorted by name
+        fn files(&self) -> Vec<(String, String)> {
+            let mut files: Vec<_> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let name = entry.file_name().into_string().unwrap();
+                    (name, fs::read_to_string(entry.path()).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = Dir::new("access-log-size");
+        let mut file = LogFile::open(&dir.0.join("access.log"), Rotation::Size(10)).unwrap();
+        for line in ["aaaa\n", "bbbb\n", "cccc\n", "dddd\n", "eeeeeeeeeeeeeeee\n"] {
+            file.write_line(line.as_bytes(), time()).unwrap();
+        }
+        // rotated in the same second, so numbered after the first
+        assert_eq!(
+            dir.files(),
+            [
+                ("access.log".to_string(), "eeeeeeeeeeeeeeee\n".to_string()),
+                (
+                    "access.log.2001-09-09T01-46-40".to_string(),
+                    "aaaa\nbbbb\n".to_string()
+                ),
+                (
+                    "access.log.2001-09-09T01-46-40.1".to_string(),
+                    "cccc\ndddd\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotates_by_day() {
+        let dir = Dir::new("access-log-daily");
+        let mut file = LogFile::open(&dir.0.join("access.log"), Rotation::Daily).unwrap();
+        // the file is new, so it starts out covering today
+        file.period = period(Rotation::Daily, time());
+        let day = Duration::from_secs(24 * 60 * 60);
+        file.write_line(b"one\n", time()).unwrap();
+        file.write_line(b"two\n", time() + Duration::from_secs(60))
+            .unwrap();
+        file.write_line(b"three\n", time() + day).unwrap();
+        assert_eq!(
+            dir.files(),
+            [
+                ("access.log".to_string(), "three\n".to_string()),
+                (
+                    "access.log.2001-09-09".to_string(),
+                    "one\ntwo\n".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn log_writes_one_line_per_entry() {
+        let dir = Dir::new("access-log-open");
+        let path = dir.0.join("access.log");
+        let log = AccessLog::open(&path, AccessLogFormat::Common, Rotation::Never).unwrap();
+        let request = browser_request();
+        log.log(&entry(Some(&request), None));
+        log.log(&entry(None, Some("BAD")));
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], common(&entry(Some(&request), None)));
+        assert!(text.ends_with('\n'));
+    }
+}
@@ -1,44 +1,390 @@
+use std::env;
 use std::fs;
-use std::io::prelude::*;
+use std::io;
+use std::iter;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::process;
+use std::sync::{Arc, Mutex, Weak};
 
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use rs_webserver::ThreadPool;
+use rs_webserver::config::{self, Config};
+use rs_webserver::{debug, error, info, warn};
+use rs_webserver::{log, signal};
+use rs_webserver::{
+    AccessLog, CancellationToken, Connection, QueuePolicy, Response, Router, ServerMetrics,
+    StaticFiles, StatusCode, ThreadPool,
+};
+
+/// Everything a connection handler needs.
+struct Site {
+    config: Config,
+    router: Router,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Arc<ServerMetrics>,
+}
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        print!("{}", config::USAGE);
+        return;
+    }
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("Try --help for usage.");
+            process::exit(2);
+        }
+    };
 
-    let pool = ThreadPool::new(4);
+    let listeners: Vec<TcpListener> = config
+        .listen
+        .iter()
+        .map(|addr| {
+            // A blocking accept() won't notice a shutdown signal, so
+            // incoming_until_shutdown polls instead.
+            TcpListener::bind(addr)
+                .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+                .unwrap_or_else(|e| {
+                    eprintln!("error: can't listen on {}: {}", addr, e);
+                    process::exit(1);
+                })
+        })
+        .collect();
+    if let Err(e) = signal::install_shutdown_handler() {
+        eprintln!("error: can't install signal handler: {}", e);
+        process::exit(1);
+    }
+    log::set_max_level(config.log_level);
+    for addr in &config.listen {
+        info!("listening on {}", addr);
+    }
 
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    let files = StaticFiles::new(&config.document_root)
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "error: can't serve {}: {}",
+                config.document_root.display(),
+                e
+            );
+            process::exit(1);
+        })
+        .with_index(config.index.clone());
+    let pool = Arc::new(
+        ThreadPool::builder()
+            .bounds(config.workers, config.max_workers.unwrap_or(config.workers))
+            .idle_timeout(config.worker_idle_timeout)
+            .queue_capacity(config.queue_capacity)
+            .queue_policy(QueuePolicy::Reject)
+            .thread_name("http")
+            .build(),
+    );
 
-        println!("Connection established!");
-        pool.execute(|| {
-            handle_connection(stream);
+    let metrics = Arc::new(ServerMetrics::new());
+    let mut router = Router::new().fallback(move |request: &_| files.serve(request));
+    router = router.get("/healthz", |_: &_| Response::text(StatusCode::Ok, "ok"));
+    let ready_pool = Arc::downgrade(&pool);
+    router = router.get("/readyz", move |_: &_| readiness(&ready_pool));
+    if let Some(path) = &config.metrics_path {
+        // Weak, so the last handle isn't dropped (and the pool shut down)
+        // from one of its own workers
+        let metrics = metrics.clone();
+        let pool: Weak<ThreadPool> = Arc::downgrade(&pool);
+        router = router.get(path, move |_: &_| {
+            let stats = pool.upgrade().map(|pool| pool.stats());
+            Response::text(StatusCode::Ok, &metrics.render(stats.as_ref()))
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
         });
     }
+    let access_log = match &config.access_log {
+        Some(path) if path.as_os_str() == "-" => {
+            Some(Arc::new(AccessLog::stdout(config.access_log_format)))
+        }
+        Some(path) => {
+            let log = AccessLog::open(path, config.access_log_format, config.access_log_rotation)
+                .unwrap_or_else(|e| {
+                    eprintln!("error: can't open {}: {}", path.display(), e);
+                    process::exit(1);
+                });
+            Some(Arc::new(log))
+        }
+        None => None,
+    };
+    let site = Arc::new(Site {
+        config,
+        router,
+        access_log,
+        metrics,
+    });
+    let config = &site.config;
+
+    pool.set_panic_handler(|panic| {
+        error!(
+            worker = panic.worker_id();
+            "panicked handling a connection: {}",
+            panic.message().unwrap_or("<non-string payload>")
+        );
+    });
+
+    let waiting = Waiting::watch();
+    let limit = config.max_connections.unwrap_or(usize::MAX);
+    for stream in incoming_until_shutdown(&listeners, config.drain_timeout).take(limit) {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        debug!(peer = display_peer(&stream); "connection established");
+        // keep a handle so we can still answer if the pool won't take it;
+        // this fails when we're out of file descriptors
+        let queued = stream
+            .try_clone()
+            .and_then(|overflow| Ok((overflow, Queued::new(&stream)?)));
+        let (overflow, queued) = match queued {
+            Ok((overflow, queued)) => (overflow, Arc::new(queued)),
+            Err(e) => {
+                warn!(peer = display_peer(&stream); "dropping connection: {}", e);
+                continue;
+            }
+        };
+        let token = queued.token.clone();
+        let job = {
+            let (queued, site) = (queued.clone(), site.clone());
+            move || {
+                if queued.start().is_ok() {
+                    handle_connection(stream, &site);
+                }
+            }
+        };
+        if pool.try_execute_with_token(&token, job).is_err() {
+            // saturated; turn the client away rather than let connections
+            // pile up in memory.
+            let response = Response::text(StatusCode::ServiceUnavailable, "Service Unavailable");
+            let _ = overflow.set_nonblocking(false);
+            let _ = open_connection(overflow, &site).refuse(response);
+        } else {
+            waiting.add(queued);
+        }
+    }
+
+    info!("shutting down");
+    if let Err(e) = pool.shutdown(config.shutdown_timeout) {
+        warn!("{}, still busy: {:?}", e, e.busy_workers());
+    }
+}
+
+/// Like `listener.incoming()` over several listeners, but stops `drain`
+/// after a shutdown signal comes in. The listeners must be non-blocking.
+fn incoming_until_shutdown(
+    listeners: &[TcpListener],
+    drain: Duration,
+) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+    let mut draining_since = None;
+    // grows while accept keeps failing, e.g. out of file descriptors, so we
+    // don't spin on it
+    let mut backoff = Duration::ZERO;
+    iter::from_fn(move || loop {
+        if signal::shutdown_requested() {
+            let since = *draining_since.get_or_insert_with(|| {
+                if !drain.is_zero() {
+                    info!("draining for {}s", drain.as_secs());
+                }
+                Instant::now()
+            });
+            if since.elapsed() >= drain {
+                return None;
+            }
+        }
+        thread::sleep(backoff);
+        for listener in listeners {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    backoff = Duration::ZERO;
+                    return Some(Ok(stream));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    backoff =
+                        (backoff * 2).clamp(Duration::from_millis(10), Duration::from_secs(1));
+                    return Some(Err(e));
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    })
+}
+
+/// A connection waiting in the pool's queue.
+///
+/// Its socket is made non-blocking until a worker picks it up, so `Waiting`
+/// can peek at it to see if the client has hung up in the meantime.
+struct Queued {
+    token: CancellationToken,
+    // our own handle on the socket, given up once a worker has it so
+    // closing the connection isn't held up by it
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl Queued {
+    fn new(stream: &TcpStream) -> io::Result<Queued> {
+        // accepted sockets don't inherit non-blocking from the listener
+        stream.set_nonblocking(true)?;
+        Ok(Queued {
+            token: CancellationToken::new(),
+            stream: Mutex::new(Some(stream.try_clone()?)),
+        })
+    }
+
+    /// Called by the worker before it touches the connection.
+    fn start(&self) -> io::Result<()> {
+        match self.stream.lock().unwrap().take() {
+            Some(stream) => stream.set_nonblocking(false),
+            None => Ok(()),
+        }
+    }
+
+    /// Cancels the job if the client has gone away.
+    fn check(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        let gone = match &*stream {
+            None => return,
+            Some(stream) => loop {
+                match stream.peek(&mut [0]) {
+                    Ok(n) => break n == 0,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // only errors that say the peer is gone count, anything
+                    // else is left for the worker to find out about
+                    Err(e) => break is_disconnect(&e),
+                }
+            },
+        };
+        if gone {
+            if let Some(stream) = stream.take() {
+                debug!(peer = display_peer(&stream); "client went away while queued");
+            }
+            self.token.cancel();
+        }
+    }
+
+    /// False once a worker has it or the client has gone.
+    fn is_waiting(&self) -> bool {
+        self.stream.lock().unwrap().is_some()
+    }
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Watches queued connections from a background thread and cancels the
+/// ones whose client disconnects before a worker gets to them, so a backed
+/// up pool doesn't spend time on them.
+#[derive(Clone)]
+struct Waiting {
+    queued: Arc<Mutex<Vec<Arc<Queued>>>>,
+}
+
+impl Waiting {
+    fn watch() -> Waiting {
+        let waiting = Waiting {
+            queued: Arc::new(Mutex::new(Vec::new())),
+        };
+        let watcher = waiting.clone();
+        thread::Builder::new()
+            .name("waiting".to_string())
+            .spawn(move || loop {
+                thread::sleep(Duration::from_millis(250));
+                // check outside the lock so accepting isn't held up by it
+                let queued = watcher.queued.lock().unwrap().clone();
+                for q in &queued {
+                    q.check();
+                }
+                watcher.queued.lock().unwrap().retain(|q| q.is_waiting());
+            })
+            .unwrap();
+        waiting
+    }
+
+    fn add(&self, queued: Arc<Queued>) {
+        self.queued.lock().unwrap().push(queued);
+    }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    thread::sleep(Duration::from_millis(4000)); //artificial delay to show how single threaded server would be slow if multiple connections
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+fn open_connection(stream: TcpStream, site: &Site) -> Connection {
+    let mut connection = Connection::new(stream, site.config.connection_options());
+    if let Some(log) = &site.access_log {
+        connection = connection.with_access_log(log.clone());
+    }
+    connection.with_metrics(site.metrics.clone())
+}
 
-    let get = b"GET / HTTP/1.1\r\n";
+fn handle_connection(stream: TcpStream, site: &Site) {
+    let config = &site.config;
+    let mut connection = open_connection(stream, site);
+    let result = connection.serve(|request| {
+        let mut response = site.router.handle(request);
+        if response.status().as_u16() >= 400 {
+            response = error_page(response, config);
+        }
+        if signal::shutdown_requested() {
+            // don't hold on to keep-alive connections while draining
+            response.headers_mut().insert("Connection", "close");
+        }
+        response
+    });
+    if let Err(e) = result {
+        info!("connection error: {}", e);
+    }
+}
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
+/// 200 while the pool can take more connections, 503 once it's full or
+/// we're shutting down, so a load balancer moves traffic elsewhere.
+fn readiness(pool: &Weak<ThreadPool>) -> Response {
+    let not_ready = if signal::shutdown_requested() {
+        Some("draining")
     } else {
-        ("HTTP/1.1 200 OK\r\n\r\n", "404.html")
+        match pool.upgrade() {
+            Some(pool) if pool.is_saturated() => Some("saturated"),
+            Some(pool) if !pool.is_shut_down() => None,
+            _ => Some("shutting down"),
+        }
     };
+    match not_ready {
+        Some(reason) => Response::text(StatusCode::ServiceUnavailable, reason),
+        None => Response::text(StatusCode::Ok, "ready"),
+    }
+}
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let response = format!("{}{}", status_line, contents);
+fn display_peer(stream: &TcpStream) -> String {
+    match stream.peer_addr() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => "-".to_string(),
+    }
+}
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+/// Swaps in the configured page for an error response, if there is one.
+fn error_page(mut response: Response, config: &Config) -> Response {
+    let page = config
+        .error_pages
+        .get(&response.status().as_u16())
+        .and_then(|page| fs::read(config.document_root.join(page)).ok());
+    if let Some(contents) = page {
+        response
+            .headers_mut()
+            .insert("Content-Type", "text/html; charset=utf-8");
+        response.set_body(contents);
+    }
+    response
 }
@@ -0,0 +1,294 @@
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::queue::{JobQueue, QueuePolicy};
+use crate::stats::Activity;
+use crate::timer::Timer;
+use crate::{Hook, Shared, Sizing, ThreadPool};
+
+/// How worker threads are spawned.
+pub(crate) struct ThreadConfig {
+    pub name_prefix: String,
+    pub stack_size: Option<usize>,
+    pub on_start: Option<Hook>,
+    pub on_stop: Option<Hook>,
+}
+
+/// Sets up a `ThreadPool` with more control than `ThreadPool::new`.
+///
+/// By default the pool has one worker per CPU, an unbounded queue and
+/// threads named `worker-<id>`.
+pub struct ThreadPoolBuilder {
+    min: usize,
+    max: usize,
+    idle_timeout: Option<Duration>,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    aging: Duration,
+    threads: ThreadConfig,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> ThreadPoolBuilder {
+        let cpus = thread::available_parallelism().map_or(4, |n| n.get());
+        ThreadPoolBuilder {
+            min: cpus,
+            max: cpus,
+            idle_timeout: Some(Duration::from_secs(60)),
+            capacity: None,
+            policy: QueuePolicy::Block,
+            aging: Duration::from_secs(1),
+            threads: ThreadConfig {
+                name_prefix: "worker".to_string(),
+                stack_size: None,
+                on_start: None,
+                on_stop: None,
+            },
+        }
+    }
+
+    /// A fixed number of workers.
+    pub fn size(self, size: usize) -> ThreadPoolBuilder {
+        self.bounds(size, size)
+    }
+
+    /// An elastic pool, see `ThreadPool::set_bounds`. `min` workers are
+    /// started up front.
+    pub fn bounds(mut self, min: usize, max: usize) -> ThreadPoolBuilder {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    /// See `ThreadPool::set_idle_timeout`.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> ThreadPoolBuilder {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Limits the queue to `capacity` jobs, see `ThreadPool::bounded`.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// What `execute` does when the queue is full. Only matters with a
+    /// `queue_capacity`.
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> ThreadPoolBuilder {
+        self.policy = policy;
+        self
+    }
+
+    /// Once a job has been queued this long it's run ahead of newer jobs
+    /// with a higher priority, so low priority work still gets through when
+    /// the pool is busy. Defaults to one second.
+    pub fn priority_aging(mut self, limit: Duration) -> ThreadPoolBuilder {
+        self.aging = limit;
+        self
+    }
+
+    /// Worker threads are named `<prefix>-<id>`, which shows up in panic
+    /// messages, debuggers and `top -H`.
+    pub fn thread_name<S: Into<String>>(mut self, prefix: S) -> ThreadPoolBuilder {
+        self.threads.name_prefix = prefix.into();
+        self
+    }
+
+    /// Stack size for worker threads in bytes, the platform default if not
+    /// set.
+    pub fn stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.threads.stack_size = Some(size);
+        self
+    }
+
+    /// Runs `f` on each worker thread as it starts, before it takes any
+    /// jobs, e.g. to set up thread-local state. Gets the worker's id.
+    ///
+    /// Replacement workers started after a crash run it too.
+    pub fn on_thread_start<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.threads.on_start = Some(Arc::new(f));
+        self
+    }
+
+    /// Runs `f` on each worker thread as it stops, whether the pool is
+    /// shutting down or shrinking. Not called for a thread that dies from a
+    /// panic.
+    pub fn on_thread_stop<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.threads.on_stop = Some(Arc::new(f));
+        self
+    }
+
+    /// Starts the pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the maximum size or queue capacity is zero, the minimum is
+    /// above the maximum, or a worker thread can't be started.
+    pub fn build(self) -> ThreadPool {
+        assert!(self.max > 0 && self.min <= self.max);
+        assert!(self.capacity != Some(0));
+        let shared = Arc::new(Shared {
+            // one shard per worker slot; a pool grown past this later just
+            // has workers sharing
+            queue: JobQueue::new(self.max, self.capacity, self.policy, self.aging),
+            workers: Mutex::new(Vec::with_capacity(self.min)),
+            sizing: Mutex::new(Sizing {
+                min: self.min,
+                max: self.max,
+                idle_timeout: self.idle_timeout,
+            }),
+            threads: self.threads,
+            next_id: AtomicUsize::new(0),
+            totals: Activity::default(),
+            panic_handler: Mutex::new(None),
+            timer: Timer::new(),
+            live: Mutex::new(0),
+            exited: Condvar::new(),
+        });
+
+        let started = {
+            let mut workers = shared.lock_workers();
+            (0..self.min).try_for_each(|_| shared.spawn_worker(&mut workers))
+        };
+        if let Err(e) = started {
+            // let the ones that did start exit rather than wait forever
+            shared.queue.close();
+            panic!("failed to spawn worker thread: {}", e);
+        }
+        ThreadPool { shared }
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+}
+
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("aging", &self.aging)
+            .field("name_prefix", &self.threads.name_prefix)
+            .field("stack_size", &self.threads.stack_size)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hint;
+    use std::sync::{mpsc, Barrier};
+
+    fn thread_name() -> String {
+        thread::current().name().unwrap_or("").to_string()
+    }
+
+    #[test]
+    fn names_threads() {
+        let pool = ThreadPool::builder().size(1).build();
+        assert_eq!(pool.spawn(thread_name).unwrap().join().unwrap(), "worker-0");
+
+        let pool = ThreadPool::builder().size(2).thread_name("http").build();
+        let (tx, rx) = mpsc::channel();
+        // holds both workers so each job lands on a different one
+        let barrier = Arc::new(Barrier::new(2));
+        for _ in 0..2 {
+            let (tx, barrier) = (tx.clone(), barrier.clone());
+            pool.execute(move || {
+                barrier.wait();
+                tx.send(thread_name()).unwrap();
+            })
+            .unwrap();
+        }
+        let mut names = vec![rx.recv().unwrap(), rx.recv().unwrap()];
+        names.sort();
+        assert_eq!(names, ["http-0", "http-1"]);
+
+        let (tx, rx) = mpsc::channel();
+        pool.schedule_after(Duration::ZERO, move || tx.send(()).unwrap())
+            .unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let timer = pool.shared.timer.stop().unwrap();
+        assert_eq!(timer.thread().name(), Some("http-timer"));
+    }
+
+    #[test]
+    fn sets_stack_size() {
+        let pool = ThreadPool::builder().size(1).stack_size(32 << 20).build();
+        let used = pool
+            .spawn(|| {
+                // would overflow the default 2 MiB stack
+                let big = hint::black_box([1u8; 8 << 20]);
+                big.iter().map(|&b| b as usize).sum::<usize>()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(used, 8 << 20);
+    }
+
+    #[test]
+    fn runs_start_and_stop_hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (on_start, on_stop) = (events.clone(), events.clone());
+        let pool = ThreadPool::builder()
+            .size(2)
+            .on_thread_start(move |id| {
+                on_start
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {} {}", id, thread_name()))
+            })
+            .on_thread_stop(move |id| on_stop.lock().unwrap().push(format!("stop {}", id)))
+            .build();
+        assert!(pool.shutdown(Duration::from_secs(5)).is_ok());
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(
+            events,
+            ["start 0 worker-0", "start 1 worker-1", "stop 0", "stop 1"]
+        );
+    }
+
+    #[test]
+    fn panicking_hooks_dont_stop_workers() {
+        let stopped = Arc::new(Mutex::new(0));
+        let counter = stopped.clone();
+        let pool = ThreadPool::builder()
+            .size(1)
+            .on_thread_start(|_| panic!("start hook failed"))
+            .on_thread_stop(move |_| {
+                *counter.lock().unwrap() += 1;
+                panic!("stop hook failed");
+            })
+            .build();
+        assert_eq!(pool.spawn(|| 5).unwrap().join().unwrap(), 5);
+        assert!(pool.shutdown(Duration::from_secs(5)).is_ok());
+        // ran once, and didn't get the worker respawned
+        assert_eq!(*stopped.lock().unwrap(), 1);
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn max_must_not_be_below_min() {
+        ThreadPool::builder().bounds(3, 2).build();
+    }
+}
@@ -0,0 +1,179 @@
+use std::io;
+use std::io::prelude::*;
+use std::str;
+
+use crate::headers::Headers;
+use crate::request::{is_field_value, is_token, ParseError};
+
+/// Writes everything passed to it as `Transfer-Encoding: chunked` data.
+///
+/// Each `write` becomes one chunk. `finish` must be called to send the
+/// terminating chunk (and any trailers), otherwise the client will keep
+/// waiting for more.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    /// Writes the last chunk and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_with_trailers(&Headers::new())
+    }
+
+    pub fn finish_with_trailers(mut self, trailers: &Headers) -> io::Result<W> {
+        let mut end = String::from("0\r\n");
+        trailers.write_fields(&mut end);
+        end.push_str("\r\n");
+        self.inner.write_all(end.as_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Chunk size lines and trailers are short; anything longer than this is
+// garbage or an attack.
+const MAX_LINE: usize = 4096;
+
+enum State {
+    Size,
+    Data(usize),
+    // the CRLF after a chunk's data
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Incremental decoder for a chunked request body.
+pub(crate) struct ChunkedDecoder {
+    state: State,
+    body: Vec<u8>,
+    trailers: Headers,
+    max_body: usize,
+    // trailer bytes we'll still accept
+    trailer_room: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new(max_body: usize, max_trailers: usize) -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: State::Size,
+            body: Vec::new(),
+            trailers: Headers::new(),
+            max_body,
+            trailer_room: max_trailers,
+        }
+    }
+
+    /// Consumes as much of `buf` as possible. Returns true once the whole
+    /// body, trailers included, has been read.
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<bool, ParseError> {
+        loop {
+            match self.state {
+                State::Size => {
+                    let line = match take_line(buf)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    // chunk extensions after `;` are allowed and ignored
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    let size =
+                        usize::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)?;
+                    if size > self.max_body - self.body.len() {
+                        return Err(ParseError::BodyTooLarge);
+                    }
+                    self.state = if size == 0 {
+                        State::Trailers
+                    } else {
+                        State::Data(size)
+                    };
+                }
+                State::Data(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(false);
+                    }
+                    let n = remaining.min(buf.len());
+                    self.body.extend(buf.drain(..n));
+                    self.state = if n == remaining {
+                        State::DataEnd
+                    } else {
+                        State::Data(remaining - n)
+                    };
+                }
+                State::DataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(false);
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(ParseError::InvalidChunk);
+                    }
+                    buf.drain(..2);
+                    self.state = State::Size;
+                }
+                State::Trailers => {
+                    let line = match take_line(buf)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+                    if line.is_empty() {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    self.trailer_room = self
+                        .trailer_room
+                        .checked_sub(line.len() + 2)
+                        .ok_or(ParseError::HeadersTooLarge)?;
+                    let colon = line.find(':').ok_or(ParseError::InvalidHeader)?;
+                    let name = &line[..colon];
+                    let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+                    if !is_token(name) || !is_field_value(value) {
+                        return Err(ParseError::InvalidHeader);
+                    }
+                    self.trailers.append(name, value);
+                }
+                State::Done => return Ok(true),
+            }
+        }
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, Headers) {
+        (self.body, self.trailers)
+    }
+}
+
+/// Takes one CRLF terminated line off the front of `buf`.
+fn take_line(buf: &mut Vec<u8>) -> Result<Option<String>, ParseError> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() > MAX_LINE => return Err(ParseError::InvalidChunk),
+        None => return Ok(None),
+    };
+    let line = str::from_utf8(&buf[..end])
+        .map_err(|_| ParseError::InvalidChunk)?
+        .to_string();
+    buf.drain(..end + 2);
+    Ok(Some(line))
+}
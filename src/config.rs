@@ -0,0 +1,807 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::access_log::{AccessLogFormat, Rotation};
+use crate::connection::ConnectionOptions;
+use crate::log::Level;
+use crate::request::Limits;
+
+pub const USAGE: &str = "\
+Usage: main [OPTIONS]
+
+Options:
+  -c, --config <FILE>           read settings from FILE, flags override it
+  -l, --listen <ADDR>           address to listen on, may be repeated
+  -w, --workers <N>             number of worker threads
+      --max-workers <N>         let the pool grow to N workers under load,
+                                0 to keep it fixed
+      --worker-idle-timeout <SECS>
+                                how long extra workers idle before stopping
+      --queue-capacity <N>      connections allowed to wait for a worker
+  -r, --root <DIR>              document root
+      --index <FILE>            file served for directories, e.g. `/`
+      --error-page <CODE=FILE>  page served with the given status
+      --header-timeout <SECS>   time allowed to send request headers, 0 for none
+      --body-timeout <SECS>     time allowed to send a request body, 0 for none
+      --write-timeout <SECS>    socket write timeout, 0 for none
+      --keep-alive-timeout <SECS>
+                                how long idle connections are kept open
+      --max-requests <N>        requests per connection, 1 disables keep-alive,
+                                0 for no limit
+      --shutdown-timeout <SECS> how long to wait for in-flight requests
+      --drain-timeout <SECS>    keep accepting this long after a shutdown
+                                signal, failing /readyz meanwhile
+      --max-connections <N>     stop after N connections, 0 for no limit
+      --max-request-line <BYTES>
+                                longest request line accepted
+      --max-headers <N>         most header fields in a request
+      --max-header-bytes <BYTES>
+                                largest header section accepted
+      --max-body <BYTES>        largest request body accepted
+      --log-level <LEVEL>       error, warn, info, debug or trace
+      --access-log <FILE>       log requests to FILE, `-` for stdout
+      --access-log-format <FORMAT>
+                                common, combined or json
+      --access-log-rotate <WHEN>
+                                never, hourly, daily or a size like 100M
+      --metrics-path <PATH>     serve Prometheus metrics at PATH, e.g. /metrics
+  -h, --help                    print this help
+";
+
+/// Server settings, from defaults, a config file and command line flags in
+/// that order of precedence.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// Worker threads the pool starts with and never drops below.
+    pub workers: usize,
+    /// Upper bound for an elastic pool, `None` keeps it at `workers`.
+    pub max_workers: Option<usize>,
+    pub worker_idle_timeout: Option<Duration>,
+    pub queue_capacity: usize,
+    pub document_root: PathBuf,
+    /// File name served when a directory is requested.
+    pub index: String,
+    /// Pages to serve for error statuses, keyed by status code.
+    pub error_pages: BTreeMap<u16, PathBuf>,
+    pub header_timeout: Option<Duration>,
+    pub body_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub keep_alive_timeout: Option<Duration>,
+    /// Requests served per connection before it's closed.
+    pub max_requests: Option<usize>,
+    pub shutdown_timeout: Duration,
+    /// How long to keep serving after a shutdown signal while `/readyz`
+    /// fails, so a load balancer can stop sending traffic first.
+    pub drain_timeout: Duration,
+    pub max_connections: Option<usize>,
+    /// Request size limits, see `Limits`.
+    pub limits: Limits,
+    pub log_level: Level,
+    /// Where to write the access log, `-` for stdout. `None` turns it off.
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    pub access_log_rotation: Rotation,
+    /// Where Prometheus metrics are served, e.g. `/metrics`. `None` turns
+    /// them off.
+    pub metrics_path: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let mut error_pages = BTreeMap::new();
+        error_pages.insert(404, PathBuf::from("404.html"));
+        Config {
+            listen: vec!["127.0.0.1:7878".parse().unwrap()],
+            workers: 4,
+            max_workers: None,
+            worker_idle_timeout: Some(Duration::from_secs(60)),
+            queue_capacity: 64,
+            document_root: PathBuf::from("."),
+            index: "hello.html".to_string(),
+            error_pages,
+            header_timeout: Some(Duration::from_secs(10)),
+            body_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            max_requests: Some(100),
+            shutdown_timeout: Duration::from_secs(10),
+            drain_timeout: Duration::ZERO,
+            max_connections: None,
+            limits: Limits::default(),
+            log_level: Level::Info,
+            access_log: None,
+            access_log_format: AccessLogFormat::Combined,
+            access_log_rotation: Rotation::Never,
+            metrics_path: None,
+        }
+    }
+}
+
+/// Where a bad setting came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    File { path: PathBuf, line: usize },
+    CommandLine,
+}
+
+/// A config file or command line that couldn't be used, pointing at the
+/// offending key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    origin: Origin,
+    key: String,
+    message: String,
+}
+
+impl ConfigError {
+    fn new<K: Into<String>, M: Into<String>>(origin: &Origin, key: K, message: M) -> ConfigError {
+        ConfigError {
+            origin: origin.clone(),
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    /// The key (or flag) that was rejected.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.origin {
+            Origin::File { path, line: 0 } => write!(f, "{}: ", path.display())?,
+            Origin::File { path, line } => write!(f, "{}:{}: ", path.display(), line)?,
+            Origin::CommandLine => {}
+        }
+        if self.key.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// A parsed value from the config file.
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    Int(i64),
+    List(Vec<String>),
+    // unquoted text from a flag, could be either a string or a number
+    Raw(String),
+}
+
+impl Config {
+    /// The per-connection settings, as used by `Connection`.
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            header_timeout: self.header_timeout,
+            body_timeout: self.body_timeout,
+            write_timeout: self.write_timeout,
+            idle_timeout: self.keep_alive_timeout,
+            max_requests: self.max_requests,
+            limits: self.limits.clone(),
+        }
+    }
+
+    /// Builds a config from command line arguments (without the program
+    /// name), loading `--config` first if it's given.
+    pub fn from_args<I, S>(args: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let origin = Origin::CommandLine;
+        let args: Vec<String> = args.into_iter().map(Into::into).collect();
+
+        let mut pairs = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let (flag, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let value = match inline {
+                Some(v) => v,
+                None => match iter.next() {
+                    Some(v) => v.clone(),
+                    None => return Err(ConfigError::new(&origin, flag, "missing value")),
+                },
+            };
+            pairs.push((flag.to_string(), value));
+        }
+
+        let mut config = match pairs.iter().find(|(f, _)| f == "-c" || f == "--config") {
+            Some((_, path)) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        // a repeated --listen replaces the file's list rather than adding to it
+        let mut listen = Vec::new();
+        for (flag, value) in pairs {
+            let key = match flag.as_str() {
+                "-c" | "--config" => continue,
+                "-l" | "--listen" => {
+                    listen.push(value.parse().map_err(|_| {
+                        ConfigError::new(&origin, flag.as_str(), "invalid socket address")
+                    })?);
+                    continue;
+                }
+                "-w" | "--workers" => "workers",
+                "--max-workers" => "max_workers",
+                "--worker-idle-timeout" => "timeouts.worker_idle",
+                "--queue-capacity" => "queue_capacity",
+                "-r" | "--root" => "document_root",
+                "--index" => "index",
+                "--error-page" => {
+                    let (code, path) = match value.find('=') {
+                        Some(i) => (&value[..i], &value[i + 1..]),
+                        None => return Err(ConfigError::new(&origin, flag, "expected CODE=FILE")),
+                    };
+                    let key = format!("error_pages.{}", code);
+                    config
+                        .set(&key, Value::Str(path.to_string()), &origin)
+                        .map_err(|e| ConfigError { key: flag, ..e })?;
+                    continue;
+                }
+                "--header-timeout" => "timeouts.header",
+                "--body-timeout" => "timeouts.body",
+                "--write-timeout" => "timeouts.write",
+                "--keep-alive-timeout" => "timeouts.keep_alive",
+                "--max-requests" => "max_requests",
+                "--shutdown-timeout" => "timeouts.shutdown",
+                "--drain-timeout" => "timeouts.drain",
+                "--max-connections" => "max_connections",
+                "--max-request-line" => "limits.request_line",
+                "--max-headers" => "limits.headers",
+                "--max-header-bytes" => "limits.header_bytes",
+                "--max-body" => "limits.body",
+                "--log-level" => "log_level",
+                "--access-log" => "access_log.path",
+                "--access-log-format" => "access_log.format",
+                "--access-log-rotate" => "access_log.rotate",
+                "--metrics-path" => "metrics_path",
+                _ => return Err(ConfigError::new(&origin, flag, "unknown option")),
+            };
+            config
+                .set(key, Value::Raw(value), &origin)
+                .map_err(|e| ConfigError { key: flag, ..e })?;
+        }
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+
+        config.check(&origin)?;
+        Ok(config)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| {
+            ConfigError::new(
+                &Origin::File {
+                    path: path.to_path_buf(),
+                    line: 0,
+                },
+                "",
+                e.to_string(),
+            )
+        })?;
+        Config::parse(&text, path)
+    }
+
+    /// Parses the contents of a config file. `path` is only used for error
+    /// messages.
+    pub fn parse(text: &str, path: &Path) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut section = String::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let origin = Origin::File {
+                path: path.to_path_buf(),
+                line: n + 1,
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(ConfigError::new(
+                        &origin,
+                        line,
+                        "unterminated section header",
+                    ));
+                }
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+
+            let eq = match line.find('=') {
+                Some(eq) => eq,
+                None => return Err(ConfigError::new(&origin, line, "expected `key = value`")),
+            };
+            let key = line[..eq].trim();
+            let key = if section.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", section, key)
+            };
+            let value = parse_value(line[eq + 1..].trim())
+                .map_err(|msg| ConfigError::new(&origin, key.as_str(), msg))?;
+            config.set(&key, value, &origin)?;
+        }
+
+        let origin = Origin::File {
+            path: path.to_path_buf(),
+            line: 0,
+        };
+        config.check(&origin)?;
+        Ok(config)
+    }
+
+    /// Checks the settings that depend on each other.
+    fn check(&self, origin: &Origin) -> Result<(), ConfigError> {
+        if self.max_workers.is_some_and(|max| max < self.workers) {
+            return Err(ConfigError::new(
+                origin,
+                "max_workers",
+                "can't be less than `workers`",
+            ));
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: Value, origin: &Origin) -> Result<(), ConfigError> {
+        let err = |msg: &str| ConfigError::new(origin, key, msg);
+
+        match key {
+            "listen" => {
+                let addrs = match value {
+                    Value::Str(s) | Value::Raw(s) => vec![s],
+                    Value::List(l) => l,
+                    _ => return Err(err("expected an address or list of addresses")),
+                };
+                if addrs.is_empty() {
+                    return Err(err("need at least one address"));
+                }
+                self.listen = addrs
+                    .iter()
+                    .map(|a| a.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| err("invalid socket address"))?;
+            }
+            "workers" => self.workers = positive(value).map_err(err)?,
+            "max_workers" => self.max_workers = optional_count(value).map_err(err)?,
+            "timeouts.worker_idle" => {
+                self.worker_idle_timeout = optional_secs(value).map_err(err)?
+            }
+            "queue_capacity" => self.queue_capacity = positive(value).map_err(err)?,
+            "document_root" => {
+                let root = PathBuf::from(string(value).map_err(err)?);
+                if !root.is_dir() {
+                    return Err(err("not a directory"));
+                }
+                self.document_root = root;
+            }
+            "index" => self.index = string(value).map_err(err)?,
+            "timeouts.header" => self.header_timeout = optional_secs(value).map_err(err)?,
+            "timeouts.body" => self.body_timeout = optional_secs(value).map_err(err)?,
+            "timeouts.write" => self.write_timeout = optional_secs(value).map_err(err)?,
+            "timeouts.keep_alive" => self.keep_alive_timeout = optional_secs(value).map_err(err)?,
+            "max_requests" => self.max_requests = optional_count(value).map_err(err)?,
+            "timeouts.shutdown" => self.shutdown_timeout = secs(value).map_err(err)?,
+            "timeouts.drain" => self.drain_timeout = secs(value).map_err(err)?,
+            "max_connections" => self.max_connections = optional_count(value).map_err(err)?,
+            "limits.request_line" => self.limits.request_line = positive(value).map_err(err)?,
+            "limits.headers" => self.limits.headers = positive(value).map_err(err)?,
+            "limits.header_bytes" => self.limits.header_bytes = positive(value).map_err(err)?,
+            "limits.body" => self.limits.body = non_negative(value).map_err(err)? as usize,
+            "log_level" => {
+                self.log_level = string(value)
+                    .map_err(err)?
+                    .parse()
+                    .map_err(|m: String| err(&m))?
+            }
+            "access_log.path" => {
+                let path = string(value).map_err(err)?;
+                self.access_log = if path.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(path))
+                };
+            }
+            "access_log.format" => {
+                self.access_log_format = string(value)
+                    .map_err(err)?
+                    .parse()
+                    .map_err(|m: String| err(&m))?
+            }
+            "access_log.rotate" => {
+                // a bare number is a size in bytes
+                let when = match value {
+                    Value::Int(n) => n.to_string(),
+                    value => string(value).map_err(err)?,
+                };
+                self.access_log_rotation = when.parse().map_err(|m: String| err(&m))?
+            }
+            "metrics_path" => {
+                let path = string(value).map_err(err)?;
+                self.metrics_path = if path.is_empty() {
+                    None
+                } else if !path.starts_with('/') {
+                    return Err(err("expected a path starting with /"));
+                } else {
+                    Some(path)
+                };
+            }
+            _ if key.starts_with("error_pages.") => {
+                let code = &key["error_pages.".len()..];
+                let code = match code.parse::<u16>() {
+                    Ok(code) if (400..600).contains(&code) => code,
+                    _ => return Err(err("expected a 4xx or 5xx status code")),
+                };
+                let page = string(value).map_err(err)?;
+                self.error_pages.insert(code, PathBuf::from(page));
+            }
+            _ => return Err(err("unknown key")),
+        }
+        Ok(())
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if s.starts_with('"') {
+        return parse_string(s).map(Value::Str);
+    }
+    if s.starts_with('[') {
+        if !s.ends_with(']') {
+            return Err("unterminated list".to_string());
+        }
+        let inner = s[1..s.len() - 1].trim();
+        let mut items = Vec::new();
+        for item in split_list(inner) {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            items.push(parse_string(item)?);
+        }
+        return Ok(Value::List(items));
+    }
+    s.replace('_', "")
+        .parse()
+        .map(Value::Int)
+        .map_err(|_| format!("can't parse `{}`, strings need quotes", s))
+}
+
+fn parse_string(s: &str) -> Result<String, String> {
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err("expected a quoted string".to_string());
+    }
+    let mut out = String::new();
+    let mut chars = s[1..s.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => out.push('"'),
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            _ => return Err("invalid escape in string".to_string()),
+        }
+    }
+    Ok(out)
+}
+
+// splits on commas that aren't inside quotes
+fn split_list(s: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                items.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&s[start..]);
+    items
+}
+
+fn string(value: Value) -> Result<String, &'static str> {
+    match value {
+        Value::Str(s) | Value::Raw(s) => Ok(s),
+        _ => Err("expected a string"),
+    }
+}
+
+fn integer(value: Value) -> Option<i64> {
+    match value {
+        Value::Int(n) => Some(n),
+        Value::Raw(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn non_negative(value: Value) -> Result<u64, &'static str> {
+    match integer(value) {
+        Some(n) if n >= 0 => Ok(n as u64),
+        _ => Err("expected a non-negative integer"),
+    }
+}
+
+fn positive(value: Value) -> Result<usize, &'static str> {
+    match integer(value) {
+        Some(n) if n > 0 => Ok(n as usize),
+        _ => Err("expected a positive integer"),
+    }
+}
+
+// 0 means no limit
+fn optional_count(value: Value) -> Result<Option<usize>, &'static str> {
+    match non_negative(value)? {
+        0 => Ok(None),
+        n => Ok(Some(n as usize)),
+    }
+}
+
+// Timeouts get added to `Instant::now()`, which panics on overflow, and
+// nobody means to wait longer than this anyway.
+const MAX_SECS: u64 = 365 * 24 * 60 * 60;
+
+fn secs(value: Value) -> Result<Duration, &'static str> {
+    match non_negative(value)? {
+        n if n > MAX_SECS => Err("can't be more than a year (31536000 seconds)"),
+        n => Ok(Duration::from_secs(n)),
+    }
+}
+
+// 0 means no timeout
+fn optional_secs(value: Value) -> Result<Option<Duration>, &'static str> {
+    match secs(value)? {
+        Duration::ZERO => Ok(None),
+        d => Ok(Some(d)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(text, Path::new("test.toml"))
+    }
+
+    fn args(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::from_args(args.iter().copied())
+    }
+
+    // a config file in the temp dir, removed again on drop
+    struct File(PathBuf);
+
+    impl File {
+        fn new(name: &str, text: &str) -> File {
+            let path =
+                env::temp_dir().join(format!("rs-webserver-{}-{}.toml", name, process::id()));
+            fs::write(&path, text).unwrap();
+            File(path)
+        }
+    }
+
+    impl Drop for File {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn parses_every_kind_of_value() {
+        let config = parse(
+            r#"
+            listen = ["127.0.0.1:80", "[::1]:8080"]  # a list
+            workers = 8
+            max_workers = 0
+            index = "index.html"
+            log_level = "debug"
+
+            [timeouts]
+            header = 0
+            body = 1_000
+            shutdown = 3
+
+            [limits]
+            body = 0
+
+            [access_log]
+            path = "access.log"
+            format = "json"
+            rotate = 1024
+
+            [error_pages]
+            500 = "oops # not a comment.html"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            [
+                "127.0.0.1:80".parse().unwrap(),
+                "[::1]:8080".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.max_workers, None);
+        assert_eq!(config.index, "index.html");
+        assert_eq!(config.log_level, Level::Debug);
+        assert_eq!(config.header_timeout, None);
+        assert_eq!(config.body_timeout, Some(Duration::from_secs(1000)));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+        assert_eq!(config.limits.body, 0);
+        assert_eq!(config.access_log, Some(PathBuf::from("access.log")));
+        assert_eq!(config.access_log_format, AccessLogFormat::Json);
+        assert_eq!(config.access_log_rotation, Rotation::Size(1024));
+        assert_eq!(
+            config.error_pages.get(&500),
+            Some(&PathBuf::from("oops # not a comment.html"))
+        );
+        // defaults are kept for anything not mentioned
+        assert_eq!(
+            config.error_pages.get(&404),
+            Some(&PathBuf::from("404.html"))
+        );
+        assert_eq!(config.max_requests, Some(100));
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let err = parse("workers = 2\n[timeouts]\nheadr = 5\n").unwrap_err();
+        assert_eq!(err.key(), "timeouts.headr");
+        assert_eq!(err.message(), "unknown key");
+        assert_eq!(
+            err.origin(),
+            &Origin::File {
+                path: PathBuf::from("test.toml"),
+                line: 3
+            }
+        );
+        assert_eq!(err.to_string(), "test.toml:3: timeouts.headr: unknown key");
+
+        let err = args(&["--wrokers", "2"]).unwrap_err();
+        assert_eq!(err.key(), "--wrokers");
+        assert_eq!(err.origin(), &Origin::CommandLine);
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        for (text, key) in [
+            ("workers = 0", "workers"),
+            ("workers = -1", "workers"),
+            ("workers = many", "workers"),
+            ("index = 5", "index"),
+            ("listen = \"nowhere\"", "listen"),
+            ("listen = []", "listen"),
+            ("log_level = \"loud\"", "log_level"),
+            ("metrics_path = \"metrics\"", "metrics_path"),
+            ("error_pages.200 = \"ok.html\"", "error_pages.200"),
+            ("index = \"unterminated", "index"),
+            ("[timeouts", "[timeouts"),
+            ("workers", "workers"),
+        ] {
+            let err = parse(text).unwrap_err();
+            assert_eq!(err.key(), key, "{}", text);
+        }
+        let err = parse("workers = 4\nmax_workers = 2\n").unwrap_err();
+        assert_eq!(err.key(), "max_workers");
+
+        let err = args(&["--workers"]).unwrap_err();
+        assert_eq!(err.message(), "missing value");
+        let err = args(&["--listen", "localhost"]).unwrap_err();
+        assert_eq!(err.key(), "--listen");
+    }
+
+    #[test]
+    fn timeouts_are_capped() {
+        let year = 365 * 24 * 60 * 60;
+        let config = parse(&format!(
+            "[timeouts]\nshutdown = {}\nkeep_alive = {}",
+            year, year
+        ))
+        .unwrap();
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(year));
+        assert_eq!(config.keep_alive_timeout, Some(Duration::from_secs(year)));
+
+        for flag in [
+            "--shutdown-timeout",
+            "--drain-timeout",
+            "--header-timeout",
+            "--worker-idle-timeout",
+        ] {
+            let too_long = (year + 1).to_string();
+            let err = args(&[flag, too_long.as_str()]).unwrap_err();
+            assert_eq!(err.key(), flag);
+            let err = args(&[flag, "18446744073709551615"]).unwrap_err();
+            assert_eq!(err.key(), flag);
+        }
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let file = File::new(
+            "config-precedence",
+            "listen = [\"127.0.0.1:1\", \"127.0.0.1:2\"]\nworkers = 2\n[timeouts]\nbody = 7\n",
+        );
+        let path = file.0.to_str().unwrap();
+        let config = args(&[
+            "--workers=6",
+            "--config",
+            path,
+            "--listen",
+            "127.0.0.1:3",
+            "--body-timeout",
+            "0",
+        ])
+        .unwrap();
+        // flags win wherever they appear, and --listen replaces the list
+        assert_eq!(config.workers, 6);
+        assert_eq!(config.listen, ["127.0.0.1:3".parse().unwrap()]);
+        assert_eq!(config.body_timeout, None);
+
+        let config = args(&["-c", path]).unwrap();
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.body_timeout, Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let err = args(&["--config", "/nonexistent/server.toml"]).unwrap_err();
+        assert!(err.to_string().starts_with("/nonexistent/server.toml: "));
+    }
+}
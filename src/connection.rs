@@ -0,0 +1,513 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::{AccessLog, Entry};
+use crate::metrics::ServerMetrics;
+use crate::request::{Limits, Method, ParseError, Parser, Request, Version};
+use crate::response::{Response, StatusCode};
+
+/// How a connection is read, written and kept alive.
+///
+/// The header and body timeouts bound the whole phase rather than each read,
+/// so a client trickling in a byte at a time still gets cut off. A client
+/// that times out part way through a request is answered with a 408.
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// How long a client has to send a request's line and headers.
+    pub header_timeout: Option<Duration>,
+    /// How long a client has to send a request's body, once the headers are
+    /// in.
+    pub body_timeout: Option<Duration>,
+    /// Socket write timeout for responses.
+    pub write_timeout: Option<Duration>,
+    /// How long an idle keep-alive connection is held open waiting for the
+    /// next request.
+    pub idle_timeout: Option<Duration>,
+    /// Requests served before the connection is closed, `None` for no limit.
+    /// `Some(1)` turns keep-alive off.
+    pub max_requests: Option<usize>,
+    pub limits: Limits,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            header_timeout: Some(Duration::from_secs(10)),
+            body_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(5)),
+            max_requests: Some(100),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// A client connection, served one request after another for as long as
+/// keep-alive allows.
+///
+/// Pipelined requests are answered in order: anything the client sends
+/// ahead stays buffered in the parser until its turn.
+pub struct Connection {
+    stream: TcpStream,
+    parser: Parser,
+    options: ConnectionOptions,
+    served: usize,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<ServerMetrics>>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, options: ConnectionOptions) -> Connection {
+        Connection {
+            stream,
+            parser: Parser::with_limits(options.limits.clone()),
+            options,
+            served: 0,
+            access_log: None,
+            metrics: None,
+        }
+    }
+
+    /// Logs every request served on this connection to `log`.
+    pub fn with_access_log(mut self, log: Arc<AccessLog>) -> Connection {
+        self.access_log = Some(log);
+        self
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Number of requests answered so far.
+    pub fn served(&self) -> usize {
+        self.served
+    }
+
+    /// Counts this connection and the requests on it in `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<ServerMetrics>) -> Connection {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Reads requests and writes back whatever `handler` makes of them until
+    /// the client or the handler asks to close, a limit is hit, or the
+    /// connection sits idle for too long.
+    ///
+    /// A handler can end the connection by setting `Connection: close` on
+    /// its response.
+    pub fn serve<H>(&mut self, mut handler: H) -> io::Result<()>
+    where
+        H: FnMut(&mut Request) -> Response,
+    {
+        let _open = self.metrics.as_ref().map(|m| m.connection_opened());
+        self.stream.set_write_timeout(self.options.write_timeout)?;
+        loop {
+            let mut request = match self.next_request() {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(ParseError::Io(ref e)) if is_timeout(e) => {
+                    // an idle client just gets let go, one that stalled part
+                    // way through a request is told why
+                    if self.parser.is_empty() {
+                        return Ok(());
+                    }
+                    let response = Response::text(StatusCode::RequestTimeout, "Request Timeout");
+                    return self.reject(response);
+                }
+                Err(ParseError::Io(e)) => return Err(e),
+                Err(ParseError::UnexpectedEof) => return Ok(()),
+                Err(e) => {
+                    let status = error_status(&e);
+                    return self.reject(Response::text(status, status.reason_phrase()));
+                }
+            };
+            self.served += 1;
+            let received = SystemTime::now();
+            let started = Instant::now();
+
+            let mut keep_alive = wants_keep_alive(&request)
+                && self
+                    .options
+                    .max_requests
+                    .is_none_or(|max| self.served < max);
+
+            let mut response = handler(&mut request);
+            if response.headers().has_token("Connection", "close") {
+                keep_alive = false;
+            }
+            if request.version() == Version::Http10
+                && response.is_streamed()
+                && !response.headers().contains("Content-Length")
+            {
+                // no chunked for 1.0, closing the connection ends the body
+                keep_alive = false;
+            }
+            if !keep_alive {
+                response.headers_mut().insert("Connection", "close");
+            } else if request.version() == Version::Http10 {
+                // 1.0 clients only keep the connection if we say so
+                response.headers_mut().insert("Connection", "keep-alive");
+            }
+
+            let status = response.status();
+            let sent = response.write_for_request(
+                &mut self.stream,
+                request.method(),
+                request.version(),
+            )?;
+            if let Some(metrics) = &self.metrics {
+                metrics.record(request.route(), status, started.elapsed());
+            }
+            if let Some(log) = &self.access_log {
+                log.log(&Entry {
+                    peer: self.stream.peer_addr().ok(),
+                    time: received,
+                    request: Some(&request),
+                    request_line: None,
+                    status,
+                    bytes: sent,
+                    duration: started.elapsed(),
+                });
+            }
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Turns the client away with `response` without reading a request,
+    /// e.g. with a 503 when the server is too busy to serve it. It's logged
+    /// like any other response.
+    pub fn refuse(&mut self, response: Response) -> io::Result<()> {
+        self.stream.set_write_timeout(self.options.write_timeout)?;
+        self.write_rejection(response, None)
+    }
+
+    // Answers a request that can't be served, logging as much of its
+    // request line as was read.
+    fn reject(&mut self, response: Response) -> io::Result<()> {
+        let line = self.parser.request_line();
+        self.write_rejection(response, line.as_deref())
+    }
+
+    fn write_rejection(&mut self, response: Response, line: Option<&str>) -> io::Result<()> {
+        let received = SystemTime::now();
+        let started = Instant::now();
+        let mut response = response.with_header("Connection", "close");
+        let status = response.status();
+        let sent = response.write_for_request(&mut self.stream, &Method::Get, Version::Http11)?;
+        if let Some(metrics) = &self.metrics {
+            // no route was matched, it never got that far
+            metrics.record(None, status, started.elapsed());
+        }
+        if let Some(log) = &self.access_log {
+            log.log(&Entry {
+                peer: self.stream.peer_addr().ok(),
+                time: received,
+                request: None,
+                request_line: line,
+                status,
+                bytes: sent,
+                duration: started.elapsed(),
+            });
+        }
+        Ok(())
+    }
+
+    fn next_request(&mut self) -> Result<Option<Request>, ParseError> {
+        // A pipelined request may already be sitting in the buffer.
+        if let Some(request) = self.parser.parse()? {
+            return Ok(Some(request));
+        }
+
+        let mut chunk = [0; 4096];
+        let mut phase = self.phase();
+        let mut since = Instant::now();
+        loop {
+            if self.phase() != phase {
+                phase = self.phase();
+                since = Instant::now();
+            }
+            let limit = match phase {
+                Phase::Idle => self.options.idle_timeout,
+                Phase::Head => self.options.header_timeout,
+                Phase::Body => self.options.body_timeout,
+            };
+            let remaining = match limit {
+                Some(limit) => match limit.checked_sub(since.elapsed()) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+                },
+                None => None,
+            };
+            self.stream.set_read_timeout(remaining)?;
+
+            let n = match self.stream.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                if self.parser.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ParseError::UnexpectedEof);
+            }
+            self.parser.feed(&chunk[..n]);
+            if let Some(request) = self.parser.parse()? {
+                return Ok(Some(request));
+            }
+        }
+    }
+
+    fn phase(&self) -> Phase {
+        if self.parser.in_body() {
+            Phase::Body
+        } else if self.served > 0 && self.parser.is_empty() {
+            Phase::Idle
+        } else {
+            // waiting on the first request counts as waiting for its head
+            Phase::Head
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    // between requests on a keep-alive connection
+    Idle,
+    Head,
+    Body,
+}
+
+fn wants_keep_alive(request: &Request) -> bool {
+    let headers = request.headers();
+    match request.version() {
+        Version::Http11 => !headers.has_token("Connection", "close"),
+        Version::Http10 => headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+/// What to answer a request that couldn't be parsed with.
+fn error_status(e: &ParseError) -> StatusCode {
+    match e {
+        ParseError::UnsupportedVersion => StatusCode::HttpVersionNotSupported,
+        ParseError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+        ParseError::RequestLineTooLong => StatusCode::UriTooLong,
+        ParseError::TooManyHeaders | ParseError::HeadersTooLarge => {
+            StatusCode::RequestHeaderFieldsTooLarge
+        }
+        ParseError::BodyTooLarge => StatusCode::PayloadTooLarge,
+        _ => StatusCode::BadRequest,
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    // Serves one connection on a loopback socket with a handler that
+    // answers with the request's path. The thread returns how many requests
+    // were served.
+    fn start(options: ConnectionOptions) -> (TcpStream, thread::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            let mut connection = Connection::new(stream, options);
+            connection
+                .serve(|request| Response::text(StatusCode::Ok, request.path()))
+                .unwrap();
+            connection.served()
+        });
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (client, server)
+    }
+
+    fn read_to_end(client: &mut TcpStream) -> String {
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    // reads until `text` has come back, for requests that keep the
+    // connection open
+    fn read_until(client: &mut TcpStream, text: &str) -> String {
+        let mut out = Vec::new();
+        let mut chunk = [0; 1024];
+        while !String::from_utf8_lossy(&out).contains(text) {
+            let n = client.read(&mut chunk).unwrap();
+            assert!(n > 0, "closed early: {:?}", String::from_utf8_lossy(&out));
+            out.extend_from_slice(&chunk[..n]);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    // bodies don't end in CRLF, so the next status line follows right on
+    fn statuses(responses: &str) -> Vec<&str> {
+        responses
+            .match_indices("HTTP/1.1 ")
+            .map(|(i, _)| {
+                let line = &responses[i..];
+                &line[..line.find("\r\n").unwrap_or(line.len())]
+            })
+            .collect()
+    }
+
+    fn quick_timeouts() -> ConnectionOptions {
+        ConnectionOptions {
+            header_timeout: Some(Duration::from_millis(100)),
+            body_timeout: Some(Duration::from_millis(100)),
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..ConnectionOptions::default()
+        }
+    }
+
+    #[test]
+    fn keeps_http11_connections_alive() {
+        let (mut client, server) = start(ConnectionOptions::default());
+        client
+            .write_all(b"GET /one HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        let first = read_until(&mut client, "/one");
+        assert!(!first.contains("Connection: close"));
+
+        client
+            .write_all(b"GET /two HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let second = read_to_end(&mut client);
+        assert!(second.contains("Connection: close\r\n"));
+        assert!(second.ends_with("/two"));
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn http10_needs_to_ask_for_keep_alive() {
+        let (mut client, server) = start(ConnectionOptions::default());
+        client
+            .write_all(b"GET /one HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let first = read_until(&mut client, "/one");
+        assert!(first.contains("Connection: keep-alive\r\n"));
+
+        client.write_all(b"GET /two HTTP/1.0\r\n\r\n").unwrap();
+        let second = read_to_end(&mut client);
+        assert!(second.contains("Connection: close\r\n"));
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order() {
+        let (mut client, server) = start(ConnectionOptions::default());
+        client
+            .write_all(
+                b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n\
+                  POST /b HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc\
+                  GET /c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let responses = read_to_end(&mut client);
+        assert_eq!(statuses(&responses).len(), 3);
+        let a = responses.find("\r\n\r\n/a").unwrap();
+        let b = responses.find("\r\n\r\n/b").unwrap();
+        let c = responses.find("\r\n\r\n/c").unwrap();
+        assert!(a < b && b < c);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let (mut client, server) = start(ConnectionOptions {
+            max_requests: Some(2),
+            ..ConnectionOptions::default()
+        });
+        client
+            .write_all(
+                b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /2 HTTP/1.1\r\nHost: x\r\n\r\n\
+                  GET /3 HTTP/1.1\r\nHost: x\r\n\r\n",
+            )
+            .unwrap();
+        let responses = read_to_end(&mut client);
+        assert_eq!(statuses(&responses).len(), 2);
+        assert!(responses.ends_with("Connection: close\r\n\r\n/2"));
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn stalled_headers_get_408() {
+        let (mut client, server) = start(quick_timeouts());
+        client.write_all(b"GET /slow HTTP/1.1\r\nHost").unwrap();
+        let response = read_to_end(&mut client);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn stalled_body_gets_408() {
+        let (mut client, server) = start(quick_timeouts());
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+        let response = read_to_end(&mut client);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn header_timeout_covers_the_whole_head() {
+        let (mut client, server) = start(quick_timeouts());
+        // each byte comes in well within the timeout, the head as a whole
+        // doesn't
+        for &b in b"GET / HTTP/1.1\r\nHost: x\r\n" {
+            if client.write_all(&[b]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let response = read_to_end(&mut client);
+        assert!(response.starts_with("HTTP/1.1 408 "), "{:?}", response);
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn idle_connections_are_closed_quietly() {
+        let (mut client, server) = start(quick_timeouts());
+        client
+            .write_all(b"GET /once HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        // the one response, then the connection just closes
+        let responses = read_to_end(&mut client);
+        assert_eq!(statuses(&responses), ["HTTP/1.1 200 OK"]);
+        assert_eq!(server.join().unwrap(), 1);
+
+        // same before the first request
+        let (mut client, server) = start(quick_timeouts());
+        assert_eq!(read_to_end(&mut client), "");
+        assert_eq!(server.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn client_closing_ends_the_connection() {
+        let (mut client, server) = start(ConnectionOptions::default());
+        client
+            .write_all(b"GET /last HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let responses = read_to_end(&mut client);
+        assert_eq!(statuses(&responses).len(), 1);
+        assert_eq!(server.join().unwrap(), 1);
+    }
+}
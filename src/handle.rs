@@ -0,0 +1,167 @@
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// Why `JobHandle::join` didn't produce a value.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The job panicked; this holds the panic payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// `join_timeout` gave up waiting. The job may still finish later.
+    Timeout,
+    /// The job was thrown away without running, e.g. evicted by
+    /// `QueuePolicy::DropOldest`, or its result was already taken.
+    Dropped,
+}
+
+impl JoinError {
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+
+    /// Turns the error back into the panic payload, if it was a panic, so it
+    /// can be rethrown with `std::panic::resume_unwind`.
+    pub fn into_panic(self) -> Option<Box<dyn Any + Send + 'static>> {
+        match self {
+            JoinError::Panicked(payload) => Some(payload),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(_) => f.write_str("job panicked"),
+            JoinError::Timeout => f.write_str("timed out waiting for job"),
+            JoinError::Dropped => f.write_str("job was dropped before completing"),
+        }
+    }
+}
+
+impl error::Error for JoinError {}
+
+/// A handle to the result of a job started with `ThreadPool::spawn`.
+pub struct JobHandle<T> {
+    result: Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    pub(crate) fn new(result: Receiver<thread::Result<T>>) -> JobHandle<T> {
+        JobHandle { result }
+    }
+
+    /// Blocks until the job finishes and returns what it returned.
+    pub fn join(self) -> Result<T, JoinError> {
+        match self.result.recv() {
+            Ok(result) => result.map_err(JoinError::Panicked),
+            Err(_) => Err(JoinError::Dropped),
+        }
+    }
+
+    /// Like `join` but waits at most `timeout`.
+    ///
+    /// On `JoinError::Timeout` the handle can be joined again later. Once a
+    /// value has been returned any further join gives `JoinError::Dropped`.
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, JoinError> {
+        match self.result.recv_timeout(timeout) {
+            Ok(result) => result.map_err(JoinError::Panicked),
+            Err(RecvTimeoutError::Timeout) => Err(JoinError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(JoinError::Dropped),
+        }
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("JobHandle { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{QueuePolicy, ThreadPool};
+    use std::panic;
+    use std::sync::mpsc;
+
+    #[test]
+    fn join_returns_the_value() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<_> = (0..4)
+            .map(|n| pool.spawn(move || n * 10).unwrap())
+            .collect();
+        let results: Vec<i32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, [0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn join_again_after_a_timeout() {
+        let pool = ThreadPool::new(1);
+        let (go, wait) = mpsc::channel::<()>();
+        let handle = pool
+            .spawn(move || {
+                wait.recv().unwrap();
+                "done"
+            })
+            .unwrap();
+        assert!(matches!(
+            handle.join_timeout(Duration::from_millis(20)),
+            Err(JoinError::Timeout)
+        ));
+        go.send(()).unwrap();
+        assert_eq!(handle.join_timeout(Duration::from_secs(5)).unwrap(), "done");
+        // the value's been taken
+        assert!(matches!(
+            handle.join_timeout(Duration::from_millis(20)),
+            Err(JoinError::Dropped)
+        ));
+        assert!(matches!(handle.join(), Err(JoinError::Dropped)));
+    }
+
+    #[test]
+    fn panics_come_back_to_the_joiner() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.spawn(|| -> u32 { panic!("boom") }).unwrap();
+        let err = handle.join().unwrap_err();
+        assert!(err.is_panic());
+        assert_eq!(err.to_string(), "job panicked");
+        let payload = err.into_panic().unwrap();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        let rethrown =
+            panic::catch_unwind(panic::AssertUnwindSafe(|| panic::resume_unwind(payload)))
+                .unwrap_err();
+        assert_eq!(rethrown.downcast_ref::<&str>(), Some(&"boom"));
+
+        // the pool carries on, and doesn't count it as a panic of its own
+        assert_eq!(pool.spawn(|| 7).unwrap().join().unwrap(), 7);
+        assert_eq!(pool.stats().panicked(), 0);
+    }
+
+    #[test]
+    fn evicted_jobs_are_dropped() {
+        let pool = ThreadPool::bounded(1, 1, QueuePolicy::DropOldest);
+        let (go, wait) = mpsc::channel::<()>();
+        let (started_tx, started) = mpsc::channel();
+        let busy = pool
+            .spawn(move || {
+                started_tx.send(()).unwrap();
+                wait.recv().unwrap();
+            })
+            .unwrap();
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        let evicted = pool.spawn(|| 1).unwrap();
+        let kept = pool.spawn(|| 2).unwrap();
+        go.send(()).unwrap();
+
+        busy.join().unwrap();
+        let err = evicted.join().unwrap_err();
+        assert!(matches!(err, JoinError::Dropped));
+        assert!(!err.is_panic());
+        assert!(err.into_panic().is_none());
+        assert_eq!(kept.join().unwrap(), 2);
+    }
+}
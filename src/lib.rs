@@ -1,7 +1,57 @@
+mod access_log;
+mod builder;
+mod cancel;
+mod chunked;
+pub mod config;
+mod connection;
+mod date;
+mod handle;
+mod headers;
+pub mod log;
+mod metrics;
+mod queue;
+mod request;
+mod response;
+mod router;
+mod scope;
+pub mod signal;
+mod static_files;
+mod stats;
+mod timer;
+mod worker;
+
+pub use crate::access_log::{AccessLog, AccessLogFormat, Rotation};
+pub use crate::builder::ThreadPoolBuilder;
+pub use crate::cancel::CancellationToken;
+pub use crate::chunked::ChunkedWriter;
+pub use crate::config::{Config, ConfigError, Origin};
+pub use crate::connection::{Connection, ConnectionOptions};
+pub use crate::handle::{JobHandle, JoinError};
+pub use crate::headers::Headers;
+pub use crate::metrics::ServerMetrics;
+pub use crate::queue::{Priority, QueuePolicy};
+pub use crate::request::{Limits, Method, ParseError, Parser, Request, Version};
+pub use crate::response::{Response, StatusCode, SERVER_NAME};
+pub use crate::router::{Handler, Router};
+pub use crate::scope::Scope;
+pub use crate::static_files::{mime_type, StaticFiles};
+pub use crate::stats::{Histogram, PoolStats, WorkerStats};
+pub use crate::timer::TimerHandle;
+pub use crate::worker::{JobPanic, Worker};
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::builder::ThreadConfig;
+use crate::queue::{JobQueue, PushError};
+use crate::stats::Activity;
+use crate::timer::{Task, Timer};
 
 // This is used to allow a function to take ownership of a boxed value.
 // According to docs, this won't be needed in future (HOPEFULLY!)
@@ -9,7 +59,6 @@ trait FnBox {
     fn call_box(self: Box<Self>);
 }
 
-
 impl<F> FnBox for F
 where
     F: FnOnce(),
@@ -21,91 +70,913 @@ where
 
 type Job = Box<dyn FnBox + Send + 'static>;
 
-enum Message {
-    NewJob(Job),
-    Terminate,
+type PanicHandler = Arc<dyn Fn(&JobPanic) + Send + Sync>;
+
+// thread start/stop hooks, given the worker id
+type Hook = Arc<dyn Fn(usize) + Send + Sync>;
+
+// A panicking job shouldn't take the whole pool down with it, so locks that
+// get poisoned along the way are just taken over.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// How many workers the pool keeps, see `ThreadPool::set_bounds`.
+struct Sizing {
+    min: usize,
+    max: usize,
+    // how long a worker above `min` may sit idle before it retires
+    idle_timeout: Option<Duration>,
 }
 
+/// State shared between the pool handle and its workers.
+struct Shared {
+    queue: JobQueue,
+    workers: Mutex<Vec<Worker>>,
+    sizing: Mutex<Sizing>,
+    threads: ThreadConfig,
+    next_id: AtomicUsize,
+    // counters for the pool's whole life, workers come and go
+    totals: Activity,
+    panic_handler: Mutex<Option<PanicHandler>>,
+    timer: Timer,
+    // number of worker threads still running, for shutdown
+    live: Mutex<usize>,
+    exited: Condvar,
+}
+
+impl Shared {
+    fn lock_workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        lock(&self.workers)
+    }
+
+    /// Starts a new worker and adds it to the registry.
+    fn spawn_worker(self: &Arc<Self>, workers: &mut Vec<Worker>) -> io::Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        workers.push(Worker::new(id, self.clone())?);
+        Ok(())
+    }
+
+    /// Adds a worker if jobs are piling up and there's room for one.
+    fn grow(self: &Arc<Self>) {
+        if self.queue.backlog() == 0 {
+            return;
+        }
+        let mut workers = self.lock_workers();
+        // don't start anything the shutdown won't know to wait for
+        if workers.len() < lock(&self.sizing).max && !self.queue.is_closed() {
+            match self.spawn_worker(&mut workers) {
+                Ok(()) => {
+                    crate::debug!(workers = workers.len(); "queue backing up, added a worker")
+                }
+                // the workers we have will get through it, just more slowly
+                Err(e) => crate::warn!(error = e; "couldn't add a worker"),
+            }
+        }
+    }
+
+    /// Called by a worker between jobs. If the pool has more workers than it
+    /// wants, takes `id` out of the registry and returns true, the worker
+    /// should then exit.
+    fn retire(&self, id: usize, idle_for: Duration) -> bool {
+        let mut workers = self.lock_workers();
+        let sizing = lock(&self.sizing);
+        let idle_too_long = sizing.idle_timeout.is_some_and(|limit| idle_for >= limit);
+        if workers.len() > sizing.max || (idle_too_long && workers.len() > sizing.min) {
+            workers.retain(|w| w.id != id);
+            return true;
+        }
+        false
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        lock(&self.sizing).idle_timeout
+    }
+
+    fn worker_started(&self) {
+        *lock(&self.live) += 1;
+    }
+
+    fn worker_exited(&self) {
+        *lock(&self.live) -= 1;
+        self.exited.notify_all();
+    }
+
+    /// Waits for every worker thread to finish, returns false if `deadline`
+    /// passes first. With no deadline it waits as long as it takes.
+    fn wait_for_exit(&self, deadline: Option<Instant>) -> bool {
+        let mut live = lock(&self.live);
+        while *live > 0 {
+            live = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.exited
+                        .wait_timeout(live, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .exited
+                    .wait(live)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        true
+    }
+
+    fn job_panicked(&self, panic: JobPanic) {
+        // clone it out so the handler can't deadlock against set_panic_handler
+        let handler = lock(&self.panic_handler).clone();
+        match handler {
+            Some(handler) => handler(&panic),
+            None => crate::error!(
+                worker = panic.worker_id();
+                "job panicked: {}",
+                panic.message().unwrap_or("<non-string payload>")
+            ),
+        }
+    }
+}
+
+/// Returned by `ThreadPool::execute` when a job couldn't be queued.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The queue is at capacity and the pool uses `QueuePolicy::Reject`.
+    QueueFull,
+    /// The pool is shutting down and no longer takes jobs.
+    ShutDown,
+    /// The thread that runs scheduled jobs couldn't be started.
+    NoTimer,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => f.write_str("job queue is full"),
+            ExecuteError::ShutDown => f.write_str("thread pool is shut down"),
+            ExecuteError::NoTimer => f.write_str("couldn't start the timer thread"),
+        }
+    }
+}
+
+impl error::Error for ExecuteError {}
+
+/// Returned by `ThreadPool::try_execute`, handing back the job that
+/// couldn't be queued.
+pub enum TryExecuteError<F> {
+    Full(F),
+    ShutDown(F),
+}
+
+impl<F> TryExecuteError<F> {
+    pub fn into_inner(self) -> F {
+        match self {
+            TryExecuteError::Full(f) | TryExecuteError::ShutDown(f) => f,
+        }
+    }
+}
+
+impl<F> fmt::Debug for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryExecuteError::Full(_) => f.write_str("Full(..)"),
+            TryExecuteError::ShutDown(_) => f.write_str("ShutDown(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryExecuteError::Full(_) => f.write_str("job queue is full"),
+            TryExecuteError::ShutDown(_) => f.write_str("thread pool is shut down"),
+        }
+    }
+}
+
+/// Returned by `ThreadPool::shutdown` when workers were still going at the
+/// deadline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownTimeout {
+    busy: Vec<usize>,
+    queued: usize,
+}
+
+impl ShutdownTimeout {
+    /// Ids of the workers still running a job.
+    pub fn busy_workers(&self) -> &[usize] {
+        &self.busy
+    }
+
+    /// Jobs that were still waiting in the queue.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+}
+
+impl fmt::Display for ShutdownTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "shutdown timed out with {} busy worker(s) and {} queued job(s)",
+            self.busy.len(),
+            self.queued
+        )
+    }
+}
+
+impl error::Error for ShutdownTimeout {}
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    queue: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
 }
 
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
-    /// The size is the number of threads in the pool.
+    /// The size is the number of threads in the pool. The job queue is
+    /// unbounded.
     ///
     /// # Panics
     ///
     /// The `new` function will panic if the size is zero or less.
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
-        let mut workers = Vec::with_capacity(size);
-        let (tx, rx) = mpsc::channel();
+        ThreadPool::builder().size(size).build()
+    }
 
-        let rx = Arc::new(Mutex::new(rx));
+    /// Create a new ThreadPool whose queue holds at most `capacity` jobs.
+    ///
+    /// `policy` decides what `execute` does once the queue is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size or capacity is zero.
+    pub fn bounded(size: usize, capacity: usize, policy: QueuePolicy) -> ThreadPool {
+        assert!(size > 0 && capacity > 0);
+        ThreadPool::builder()
+            .size(size)
+            .queue_capacity(capacity)
+            .queue_policy(policy)
+            .build()
+    }
 
-        for id in 0..size {
-            workers.push(Worker::new(id, rx.clone()));
-        }
-        ThreadPool { workers, queue: tx }
+    /// Starts configuring a pool with thread names, hooks and so on.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    /// Queue `f` to run on one of the workers.
+    ///
+    /// If the queue is full this blocks, fails or evicts the oldest job
+    /// depending on the pool's `QueuePolicy`.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, f)
+    }
+
+    /// Like `execute`, but when the pool is backed up `f` runs ahead of (or
+    /// behind) jobs of other priorities. `execute` is `Priority::Normal`.
+    ///
+    /// Lower priority jobs aren't starved: once one has waited longer than
+    /// the pool's `priority_aging` limit it goes ahead of newer jobs. With
+    /// `QueuePolicy::DropOldest` the lowest priority jobs are dropped first.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.push(Box::new(f), priority, None)
+    }
+
+    /// Like `execute`, but `f` is dropped without running if `token` is
+    /// cancelled before a worker gets to it. Once it's running it's up to
+    /// `f` to check the token (move a clone in) and stop early.
+    ///
+    /// Cancelled jobs still count towards `queued` until a worker comes
+    /// across them or room is needed in a full queue.
+    pub fn execute_with_token<F>(&self, token: &CancellationToken, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.push(Box::new(f), Priority::Normal, Some(token.clone()))
+    }
+
+    fn push(
+        &self,
+        job: Job,
+        priority: Priority,
+        token: Option<CancellationToken>,
+    ) -> Result<(), ExecuteError> {
+        self.shared
+            .queue
+            .push(job, priority, token)
+            .map_err(|e| match e {
+                PushError::Full(_) => ExecuteError::QueueFull,
+                PushError::Closed(_) => ExecuteError::ShutDown,
+            })?;
+        self.shared.grow();
+        Ok(())
+    }
+
+    /// Like `execute`, but returns a handle that can be joined to get the
+    /// closure's return value.
+    ///
+    /// A panic in `f` is caught and handed to whoever joins the handle
+    /// instead of the pool's panic handler.
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // nobody's listening if the handle was dropped, that's fine
+            let _ = tx.send(result);
+        })?;
+        Ok(JobHandle::new(rx))
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Runs `f` with a `Scope` whose jobs can borrow from the caller's
+    /// stack, so there's no need to `Arc` everything up. Doesn't return
+    /// until every job spawned in the scope has finished.
+    ///
+    /// If `f` or any of the jobs panicked the panic is carried on from here
+    /// once they're all done; with several, the first one wins.
+    ///
+    /// Don't call this from a job on the same pool: with every worker
+    /// waiting on a scope there'd be nobody left to run the jobs.
+    ///
+    /// ```text
+    /// let mut chunks = vec![vec![1, 2], vec![3, 4]];
+    /// pool.scope(|s| {
+    ///     for chunk in &mut chunks {
+    ///         s.spawn(move || chunk.iter_mut().for_each(|n| *n *= 2));
+    ///     }
+    /// });
+    /// ```
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        scope::scope(self, f)
+    }
+
+    /// Queue `f` only if there's room right now, whatever the pool's policy.
+    ///
+    /// On failure the closure is handed back so the caller can deal with it,
+    /// e.g. answer `503 Service Unavailable` itself.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_push(f, None)
+    }
+
+    /// `try_execute` with a cancellation token, see `execute_with_token`.
+    pub fn try_execute_with_token<F>(
+        &self,
+        token: &CancellationToken,
+        f: F,
+    ) -> Result<(), TryExecuteError<F>>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Message::NewJob(Box::new(f));
-        &self.queue.send(job).unwrap();
+        self.try_push(f, Some(token.clone()))
+    }
+
+    fn try_push<F>(&self, f: F, token: Option<CancellationToken>) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared
+            .queue
+            .try_push(f, |f| Box::new(f), Priority::Normal, token)
+            .map_err(|e| match e {
+                PushError::Full(f) => TryExecuteError::Full(f),
+                PushError::Closed(f) => TryExecuteError::ShutDown(f),
+            })?;
+        self.shared.grow();
+        Ok(())
+    }
+
+    /// Runs `f` on one of the workers once `delay` has passed.
+    ///
+    /// When it comes due the job is queued like any other, so the pool's
+    /// `QueuePolicy` applies; with `Reject` it's dropped if the queue is
+    /// full. Anything not yet due when the pool shuts down is dropped.
+    pub fn schedule_after<F>(&self, delay: Duration, f: F) -> Result<TimerHandle, ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.schedule(timer::after(Instant::now(), delay), Task::Once(Box::new(f)))
+    }
+
+    /// Runs `f` on one of the workers every `interval`, starting one
+    /// interval from now, until the handle is cancelled or the pool shuts
+    /// down. Handy for housekeeping like cache eviction.
+    ///
+    /// Runs never overlap: if the last one is still going, or still queued,
+    /// when the next is due, that tick is skipped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn schedule_every<F>(&self, interval: Duration, f: F) -> Result<TimerHandle, ExecuteError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero());
+        let task = Task::Every {
+            interval,
+            f: Arc::new(f),
+            running: Arc::new(AtomicBool::new(false)),
+        };
+        self.schedule(timer::after(Instant::now(), interval), task)
+    }
+
+    fn schedule(&self, due: Instant, task: Task) -> Result<TimerHandle, ExecuteError> {
+        if self.is_shut_down() {
+            return Err(ExecuteError::ShutDown);
+        }
+        self.shared.timer.add(&self.shared, due, task)
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    /// The maximum number of queued jobs, `None` if unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.shared.queue.capacity()
+    }
+
+    /// True when a bounded queue is full, so `try_execute` would be turned
+    /// away.
+    pub fn is_saturated(&self) -> bool {
+        self.capacity().is_some_and(|cap| self.queued() >= cap)
+    }
+
+    /// Number of worker threads.
+    pub fn size(&self) -> usize {
+        self.shared.lock_workers().len()
+    }
+
+    /// A snapshot of queue depth, worker activity and job timings.
+    pub fn stats(&self) -> PoolStats {
+        let workers = self
+            .shared
+            .lock_workers()
+            .iter()
+            .map(Worker::stats)
+            .collect();
+        let totals = &self.shared.totals;
+        PoolStats {
+            queued: self.shared.queue.len(),
+            workers,
+            completed: totals.completed.load(Ordering::Relaxed),
+            panicked: totals.panicked.load(Ordering::Relaxed),
+            cancelled: self.shared.queue.cancelled(),
+            wait: totals.wait.snapshot(),
+            run: totals.run.snapshot(),
+        }
+    }
+
+    /// Lets the pool grow and shrink with the load.
+    ///
+    /// The pool keeps at least `min` workers and adds more, up to `max`,
+    /// whenever jobs are queued with no idle worker to take them. Workers
+    /// beyond `min` retire after sitting idle for the idle timeout.
+    ///
+    /// Takes effect right away: workers are started to reach `min`, and if
+    /// there are more than `max` the idle ones stop now and busy ones once
+    /// their job is done.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a worker thread can't be started. The new bounds still apply
+    /// and the pool keeps the workers it managed to start.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or less than `min`.
+    pub fn set_bounds(&self, min: usize, max: usize) -> io::Result<()> {
+        assert!(max > 0 && min <= max);
+        let mut workers = self.shared.lock_workers();
+        {
+            let mut sizing = lock(&self.shared.sizing);
+            sizing.min = min;
+            sizing.max = max;
+        }
+        if self.shared.queue.is_closed() {
+            return Ok(());
+        }
+        while workers.len() < min {
+            self.shared.spawn_worker(&mut workers)?;
+        }
+        if workers.len() > max {
+            drop(workers);
+            self.shared.queue.wake_all();
+        }
+        Ok(())
+    }
+
+    /// Fixes the pool at `n` workers, starting or stopping workers to get
+    /// there. Shorthand for `set_bounds(n, n)`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a worker thread can't be started, see `set_bounds`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn resize(&self, n: usize) -> io::Result<()> {
+        self.set_bounds(n, n)
+    }
+
+    /// How long a worker above the minimum may sit idle before it's retired,
+    /// a minute by default. `None` keeps them around.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        lock(&self.shared.sizing).idle_timeout = timeout;
+        // waiting workers pick the new timeout up on their next round
+        self.shared.queue.wake_all();
+    }
+
+    pub fn min_workers(&self) -> usize {
+        lock(&self.shared.sizing).min
+    }
+
+    pub fn max_workers(&self) -> usize {
+        lock(&self.shared.sizing).max
+    }
+
+    /// Stop taking new jobs, let the workers drain the queue, and wait up to
+    /// `timeout` for them to finish.
+    ///
+    /// If some are still busy at the deadline they're left running in the
+    /// background and reported in the error; dropping the pool afterwards
+    /// won't wait for them either.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), ShutdownTimeout> {
+        // a timeout too long to represent is as good as none
+        let deadline = Instant::now().checked_add(timeout);
+        self.close();
+
+        if self.shared.wait_for_exit(deadline) {
+            self.join_workers();
+            return Ok(());
+        }
+        let busy = self
+            .shared
+            .lock_workers()
+            .iter()
+            .filter(|w| w.is_busy())
+            .map(|w| w.id())
+            .collect();
+        Err(ShutdownTimeout {
+            busy,
+            queued: self.shared.queue.len(),
+        })
+    }
+
+    /// Stops the timer and the queue. Whatever's queued still runs.
+    fn close(&self) {
+        let timer = self.shared.timer.stop();
+        self.shared.queue.close();
+        if let Some(timer) = timer {
+            let _ = timer.join();
+        }
+    }
+
+    /// True once `shutdown` has been called.
+    pub fn is_shut_down(&self) -> bool {
+        self.shared.queue.is_closed()
+    }
+
+    fn join_workers(&self) {
+        // Workers stay registered while we wait on them, so one that dies
+        // meanwhile can put its replacement in its slot for us to join next.
+        loop {
+            let next = self
+                .shared
+                .lock_workers()
+                .iter_mut()
+                .find_map(|w| w.thread.take().map(|thread| (w.id, thread)));
+            let (id, thread) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            crate::debug!(worker = id; "shutting down worker");
+
+            // a panic has already been reported, nothing more to do
+            let _ = thread.join();
+        }
+        self.shared.lock_workers().clear();
+    }
+
+    /// Call `handler` whenever a job panics, e.g. to log it.
+    ///
+    /// The worker carries on with the next job either way; if the handler
+    /// itself panics the worker is replaced.
+    pub fn set_panic_handler<H>(&self, handler: H)
+    where
+        H: Fn(&JobPanic) + Send + Sync + 'static,
+    {
+        *lock(&self.shared.panic_handler) = Some(Arc::new(handler));
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        // Send termination message to workers.
-        // two loops required since they wont receive message in order.
-        for _ in &mut self.workers {
-            self.queue.send(Message::Terminate).unwrap();
+        if self.is_shut_down() {
+            // shutdown() already did the waiting, don't hang on stragglers.
+            return;
         }
 
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                println!("Shutting down worker {}", worker.id);
+        // Closing the queue lets workers finish what's already queued and
+        // then exit.
+        self.close();
+        self.join_workers();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
 
-                thread.join().unwrap();
+    // far more than any system will hand out, so spawning always fails
+    const HUGE_STACK: usize = 1 << 60;
+
+    // polls `cond` for up to a few seconds
+    fn eventually(mut cond: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if cond() {
+                return true;
             }
+            thread::sleep(Duration::from_millis(5));
         }
+        cond()
     }
-}
 
-pub struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
+    // Keeps handing the pool jobs that block until `release` is dropped,
+    // each one only once the last has started, so every job after the first
+    // finds all the workers busy.
+    fn occupy(pool: &ThreadPool, jobs: usize) -> mpsc::Sender<()> {
+        let (release, held) = mpsc::channel::<()>();
+        let held = Arc::new(Mutex::new(held));
+        let (started_tx, started) = mpsc::channel();
+        for _ in 0..jobs {
+            let (held, started_tx) = (held.clone(), started_tx.clone());
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                let _ = lock(&held).recv();
+            })
+            .unwrap();
+            started.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        release
+    }
 
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            loop {
-                // get reference to mutex. lock it. receive job.
-                let message: Message = receiver.as_ref().lock().unwrap().recv().unwrap();
-                match message {
-                    Message::NewJob(j) => {
-                        println!("Worker {} got a job; executing.", id);
-                        j.call_box()
-                    }
-                    Message::Terminate => {
-                        println!("Worker {} terminating.", id);
-                        break;
-                    }
-                }
-            }
+    #[test]
+    fn resize_down_right_after_up() {
+        let pool = ThreadPool::builder().size(1).idle_timeout(None).build();
+        for _ in 0..20 {
+            pool.resize(8).unwrap();
+            pool.resize(1).unwrap();
+            assert!(eventually(|| pool.size() <= 1), "{} workers", pool.size());
+        }
+    }
+
+    #[test]
+    fn grows_up_to_max_when_busy() {
+        let pool = ThreadPool::builder()
+            .bounds(1, 3)
+            .idle_timeout(None)
+            .build();
+        assert_eq!(pool.size(), 1);
+        let release = occupy(&pool, 3);
+        assert_eq!(pool.size(), 3);
+
+        // no room for another, this one waits in the queue
+        pool.execute(|| {}).unwrap();
+        assert_eq!(pool.size(), 3);
+        assert_eq!(pool.queued(), 1);
+        drop(release);
+        assert!(eventually(|| pool.queued() == 0));
+    }
+
+    #[test]
+    fn idle_workers_retire_down_to_min() {
+        let pool = ThreadPool::builder()
+            .bounds(1, 3)
+            .idle_timeout(Some(Duration::from_millis(20)))
+            .build();
+        drop(occupy(&pool, 3));
+        assert!(eventually(|| pool.size() == 1), "{} workers", pool.size());
+        // and the last one stays
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn resize_starts_and_stops_workers() {
+        let pool = ThreadPool::new(2);
+        pool.resize(5).unwrap();
+        assert_eq!(pool.size(), 5);
+        assert_eq!((pool.min_workers(), pool.max_workers()), (5, 5));
+        pool.resize(2).unwrap();
+        assert!(eventually(|| pool.size() == 2), "{} workers", pool.size());
+    }
+
+    #[test]
+    fn set_bounds_lets_busy_workers_finish() {
+        let pool = ThreadPool::builder()
+            .bounds(1, 3)
+            .idle_timeout(None)
+            .build();
+        let release = occupy(&pool, 3);
+        pool.set_bounds(1, 1).unwrap();
+        // all three are still busy
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.size(), 3);
+        drop(release);
+        assert!(eventually(|| pool.size() == 1), "{} workers", pool.size());
+    }
+
+    #[test]
+    fn set_bounds_reports_spawn_failure() {
+        let pool = ThreadPool::builder()
+            .bounds(0, 2)
+            .stack_size(HUGE_STACK)
+            .build();
+        assert!(pool.set_bounds(2, 2).is_err());
+        assert_eq!(pool.size(), 0);
+        assert_eq!((pool.min_workers(), pool.max_workers()), (2, 2));
+    }
+
+    #[test]
+    fn grow_survives_spawn_failure() {
+        let pool = ThreadPool::builder()
+            .bounds(0, 2)
+            .stack_size(HUGE_STACK)
+            .build();
+        pool.execute(|| {}).unwrap();
+        assert_eq!(pool.size(), 0);
+        assert_eq!(pool.queued(), 1);
+    }
+
+    #[test]
+    fn shutdown_with_an_endless_timeout() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+        assert!(pool.shutdown(Duration::MAX).is_ok());
+        rx.try_recv().unwrap();
+    }
+
+    #[test]
+    fn recovers_from_panicking_jobs() {
+        let pool = ThreadPool::new(2);
+        for _ in 0..3 {
+            pool.execute(|| panic!("job failed")).unwrap();
+        }
+        let (tx, rx) = mpsc::channel();
+        for n in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(n).unwrap()).unwrap();
+        }
+        let mut ran: Vec<i32> = (0..4)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        ran.sort_unstable();
+        assert_eq!(ran, [0, 1, 2, 3]);
+        assert!(eventually(|| pool.stats().panicked() == 3));
+        assert_eq!(pool.size(), 2);
+    }
+
+    #[test]
+    fn replaces_a_worker_whose_panic_handler_panics() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        pool.set_panic_handler(move |panic| {
+            lock(&tx).send(panic.worker_id()).unwrap();
+            panic!("handler failed too");
         });
+        for _ in 0..3 {
+            pool.execute(|| panic!("job failed")).unwrap();
+            // the replacement keeps the id
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+        }
+        let (done, finished) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap()).unwrap();
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(pool.size(), 1);
+        assert!(pool.shutdown(Duration::from_secs(5)).is_ok());
+    }
 
-        Worker {
-            id,
-            thread: Some(thread),
+    #[test]
+    fn replacements_respect_a_shrink() {
+        let pool = ThreadPool::builder().size(2).idle_timeout(None).build();
+        pool.set_panic_handler(|_| panic!("handler failed too"));
+        let (release, held) = mpsc::channel::<()>();
+        let held = Arc::new(Mutex::new(held));
+        for _ in 0..2 {
+            let held = held.clone();
+            pool.execute(move || {
+                let _ = lock(&held).recv();
+                panic!("job failed");
+            })
+            .unwrap();
         }
+        assert!(eventually(|| pool.stats().busy_workers() == 2));
+        pool.resize(1).unwrap();
+        drop(release);
+        assert!(eventually(|| pool.size() == 1), "{} workers", pool.size());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn shutdown_reports_busy_workers() {
+        let pool = ThreadPool::new(1);
+        let release = occupy(&pool, 1);
+        pool.execute(|| {}).unwrap();
+        pool.execute(|| {}).unwrap();
+
+        let err = pool.shutdown(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.busy_workers(), [0]);
+        assert_eq!(err.queued(), 2);
+        assert!(pool.is_shut_down());
+        assert_eq!(pool.execute(|| {}), Err(ExecuteError::ShutDown));
+        drop(release);
+    }
+
+    #[test]
+    fn cancelled_jobs_are_dropped_unrun() {
+        let pool = ThreadPool::new(1);
+        let release = occupy(&pool, 1);
+        let token = CancellationToken::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        pool.execute_with_token(&token, move || flag.store(true, Ordering::SeqCst))
+            .unwrap();
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+
+        token.cancel();
+        drop(release);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(pool.stats().cancelled(), 1);
+    }
+
+    #[test]
+    fn running_jobs_can_watch_their_token() {
+        let pool = ThreadPool::new(1);
+        let token = CancellationToken::new();
+        let (tx, rx) = mpsc::channel();
+        let job_token = token.clone();
+        pool.execute_with_token(&token, move || {
+            tx.send("started").unwrap();
+            while !job_token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            tx.send("stopped").unwrap();
+        })
+        .unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "started");
+        token.cancel();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "stopped");
+        assert_eq!(pool.stats().cancelled(), 0);
+    }
+
+    #[test]
+    fn cancelled_jobs_make_room_for_try_execute() {
+        let pool = ThreadPool::bounded(1, 1, QueuePolicy::Reject);
+        let release = occupy(&pool, 1);
+        let token = CancellationToken::new();
+        pool.try_execute_with_token(&token, || {}).unwrap();
+        assert_eq!(pool.queued(), 1);
+        match pool.try_execute_with_token(&CancellationToken::new(), || {}) {
+            Err(TryExecuteError::Full(_)) => {}
+            _ => panic!("expected a full queue"),
+        }
+
+        token.cancel();
+        let (tx, rx) = mpsc::channel();
+        pool.try_execute(move || tx.send(()).unwrap()).unwrap();
+        assert_eq!(pool.queued(), 1);
+        drop(release);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(pool.stats().cancelled(), 1);
     }
 }
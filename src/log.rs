@@ -0,0 +1,332 @@
+//! A small logging facade.
+//!
+//! The pool, workers and server log through the `error!` .. `trace!` macros.
+//! By default records at `Info` and above go to stderr; `set_logger` routes
+//! them somewhere else (a closure that does nothing silences them) and
+//! `set_max_level` changes how chatty it is.
+//!
+//! Records can carry structured fields ahead of the message, as in
+//! `info!(worker = id, duration_us = elapsed; "job finished")`.
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::SystemTime;
+
+use crate::date;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err("expected one of error, warn, info, debug, trace".to_string()),
+        }
+    }
+}
+
+/// One log message along with where it came from and its fields.
+pub struct Record<'a> {
+    level: Level,
+    target: &'a str,
+    fields: &'a [(&'a str, &'a dyn fmt::Display)],
+    args: fmt::Arguments<'a>,
+}
+
+impl<'a> Record<'a> {
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// The module the record was logged from, e.g. `rs_webserver::worker`.
+    pub fn target(&self) -> &'a str {
+        self.target
+    }
+
+    /// Structured `key = value` pairs, in the order they were given.
+    pub fn fields(&self) -> &'a [(&'a str, &'a dyn fmt::Display)] {
+        self.fields
+    }
+
+    /// The formatted message.
+    pub fn args(&self) -> &fmt::Arguments<'a> {
+        &self.args
+    }
+}
+
+/// Somewhere to send log records.
+///
+/// Implemented for any `Fn(&Record)` closure.
+pub trait Logger: Send + Sync + 'static {
+    fn log(&self, record: &Record);
+}
+
+impl<F> Logger for F
+where
+    F: Fn(&Record) + Send + Sync + 'static,
+{
+    fn log(&self, record: &Record) {
+        self(record)
+    }
+}
+
+/// The default logger, writes one line per record to stderr:
+///
+/// ```text
+/// 2026-01-02T03:04:05Z INFO rs_webserver::worker: job finished worker=1 duration_us=250
+/// ```
+pub struct StderrLogger;
+
+impl Logger for StderrLogger {
+    fn log(&self, record: &Record) {
+        let line = format_line(record, SystemTime::now());
+        // one write so lines from different threads don't interleave
+        let _ = io::stderr().write_all(line.as_bytes());
+    }
+}
+
+fn format_line(record: &Record, time: SystemTime) -> String {
+    let mut line = format!(
+        "{} {} {}: {}",
+        date::rfc3339(time),
+        record.level(),
+        record.target(),
+        record.args()
+    );
+    for (key, value) in record.fields() {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line.push('\n');
+    line
+}
+
+static LOGGER: RwLock<Option<Arc<dyn Logger>>> = RwLock::new(None);
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Sends all further records to `logger` instead of stderr.
+pub fn set_logger<L: Logger>(logger: L) {
+    *LOGGER.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(logger));
+}
+
+/// Records less severe than `level` are dropped without being formatted.
+/// `Info` by default.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::ALL[MAX_LEVEL.load(Ordering::Relaxed)]
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+// Used by the macros; not part of the API.
+#[doc(hidden)]
+pub fn __log(
+    level: Level,
+    target: &str,
+    fields: &[(&str, &dyn fmt::Display)],
+    args: fmt::Arguments,
+) {
+    let record = Record {
+        level,
+        target,
+        fields,
+        args,
+    };
+    // clone it out so a slow sink doesn't hold the lock
+    let logger = LOGGER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    match logger {
+        Some(logger) => logger.log(&record),
+        None => StderrLogger.log(&record),
+    }
+}
+
+/// Logs at the given level, e.g. `log!(Level::Warn, worker = 3; "slow job")`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::log::enabled(level) {
+            $crate::log::__log(
+                level,
+                module_path!(),
+                &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+],
+                format_args!($($arg)+),
+            );
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::log::enabled(level) {
+            $crate::log::__log(level, module_path!(), &[], format_args!($($arg)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, Once};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // The logger and level are global and the rest of the crate's tests log
+    // too, so records from here are captured and everything else is passed
+    // on to stderr. The tests take turns.
+    static CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static TURN: Mutex<()> = Mutex::new(());
+
+    fn capture() -> MutexGuard<'static, ()> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_logger(|record: &Record| {
+                if record.target() == module_path!() {
+                    let line = format_line(record, UNIX_EPOCH);
+                    CAPTURED.lock().unwrap().push(line);
+                } else {
+                    StderrLogger.log(record);
+                }
+            })
+        });
+        let turn = TURN.lock().unwrap_or_else(PoisonError::into_inner);
+        CAPTURED.lock().unwrap().clear();
+        turn
+    }
+
+    fn captured() -> Vec<String> {
+        CAPTURED.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn formats_fields_after_the_message() {
+        let _turn = capture();
+        let id = 3;
+        let elapsed = Duration::from_micros(250);
+        crate::info!(worker = id, duration_us = elapsed.as_micros(); "job {}", "finished");
+        crate::warn!("plain {}", 1);
+        crate::log!(Level::Error, path = "/x"; "no args");
+        assert_eq!(
+            captured(),
+            [
+                "1970-01-01T00:00:00Z INFO rs_webserver::log::tests: job finished \
+                 worker=3 duration_us=250\n",
+                "1970-01-01T00:00:00Z WARN rs_webserver::log::tests: plain 1\n",
+                "1970-01-01T00:00:00Z ERROR rs_webserver::log::tests: no args path=/x\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let _turn = capture();
+        set_max_level(Level::Warn);
+        assert_eq!(max_level(), Level::Warn);
+        assert!(enabled(Level::Error) && enabled(Level::Warn));
+        assert!(!enabled(Level::Info));
+
+        let mut formatted = false;
+        crate::error!("kept");
+        crate::info!(
+            expensive = {
+                formatted = true;
+                1
+            };
+            "dropped"
+        );
+        crate::trace!("dropped");
+        set_max_level(Level::Trace);
+        crate::trace!("kept too");
+        set_max_level(Level::Info);
+
+        let lines = captured();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" ERROR rs_webserver::log::tests: kept\n"));
+        assert!(lines[1].ends_with(" TRACE rs_webserver::log::tests: kept too\n"));
+        // fields of a disabled record aren't even evaluated
+        assert!(!formatted);
+    }
+
+    #[test]
+    fn parses_levels() {
+        for level in Level::ALL {
+            assert_eq!(level.as_str().parse(), Ok(level));
+        }
+        assert_eq!("Debug".parse(), Ok(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
+        assert_eq!(format!("[{:5}]", Level::Warn), "[WARN ]");
+        assert!(Level::Error < Level::Trace);
+    }
+}
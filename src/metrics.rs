@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::lock;
+use crate::response::StatusCode;
+use crate::stats::{Histogram, PoolStats};
+
+// route label for requests no route matched, e.g. static files
+const UNMATCHED: &str = "unmatched";
+
+#[derive(Default)]
+struct RouteStats {
+    by_status: BTreeMap<u16, u64>,
+    latency: Histogram,
+}
+
+/// Request counters for the server, exported along with pool stats in the
+/// Prometheus text format.
+///
+/// Hook it up with `Connection::with_metrics`. Requests are labelled with
+/// the pattern of the route that answered them, so the number of series
+/// stays bounded however many distinct paths clients hit.
+#[derive(Default)]
+pub struct ServerMetrics {
+    routes: Mutex<BTreeMap<String, RouteStats>>,
+    open_connections: AtomicUsize,
+}
+
+impl ServerMetrics {
+    pub fn new() -> ServerMetrics {
+        ServerMetrics::default()
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> OpenConnection {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection {
+            metrics: self.clone(),
+        }
+    }
+
+    pub(crate) fn record(&self, route: Option<&str>, status: StatusCode, latency: Duration) {
+        let mut routes = lock(&self.routes);
+        let stats = routes
+            .entry(route.unwrap_or(UNMATCHED).to_string())
+            .or_default();
+        *stats.by_status.entry(status.as_u16()).or_default() += 1;
+        stats.latency.record(latency);
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    /// Renders everything in the Prometheus text exposition format,
+    /// including `pool` if given.
+    pub fn render(&self, pool: Option<&PoolStats>) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests answered, by route and status.",
+        );
+        let routes = lock(&self.routes);
+        for (route, stats) in routes.iter() {
+            for (status, count) in &stats.by_status {
+                let _ = writeln!(
+                    out,
+                    "http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    escape(route),
+                    status,
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time from a request being read to its response being written.",
+        );
+        for (route, stats) in routes.iter() {
+            let labels = format!("route=\"{}\"", escape(route));
+            histogram(
+                &mut out,
+                "http_request_duration_seconds",
+                &labels,
+                &stats.latency,
+            );
+        }
+        drop(routes);
+
+        header(
+            &mut out,
+            "http_open_connections",
+            "gauge",
+            "Client connections currently being served.",
+        );
+        let _ = writeln!(out, "http_open_connections {}", self.open_connections());
+
+        if let Some(pool) = pool {
+            render_pool(&mut out, pool);
+        }
+        out
+    }
+}
+
+/// Keeps a connection counted in `ServerMetrics` while it's alive.
+pub(crate) struct OpenConnection {
+    metrics: Arc<ServerMetrics>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.metrics
+            .open_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render_pool(out: &mut String, pool: &PoolStats) {
+    header(
+        out,
+        "threadpool_queued_jobs",
+        "gauge",
+        "Jobs waiting for a worker.",
+    );
+    let _ = writeln!(out, "threadpool_queued_jobs {}", pool.queued());
+
+    header(
+        out,
+        "threadpool_workers",
+        "gauge",
+        "Worker threads by state.",
+    );
+    let _ = writeln!(
+        out,
+        "threadpool_workers{{state=\"busy\"}} {}",
+        pool.busy_workers()
+    );
+    let _ = writeln!(
+        out,
+        "threadpool_workers{{state=\"idle\"}} {}",
+        pool.idle_workers()
+    );
+
+    header(
+        out,
+        "threadpool_utilization",
+        "gauge",
+        "Fraction of workers running a job.",
+    );
+    let utilization = if pool.workers().is_empty() {
+        0.0
+    } else {
+        pool.busy_workers() as f64 / pool.workers().len() as f64
+    };
+    let _ = writeln!(out, "threadpool_utilization {}", utilization);
+
+    header(
+        out,
+        "threadpool_jobs_completed_total",
+        "counter",
+        "Jobs finished, including ones that panicked.",
+    );
+    let _ = writeln!(out, "threadpool_jobs_completed_total {}", pool.completed());
+    header(
+        out,
+        "threadpool_jobs_panicked_total",
+        "counter",
+        "Jobs that panicked.",
+    );
+    let _ = writeln!(out, "threadpool_jobs_panicked_total {}", pool.panicked());
+    header(
+        out,
+        "threadpool_jobs_cancelled_total",
+        "counter",
+        "Jobs dropped because they were cancelled before starting.",
+    );
+    let _ = writeln!(out, "threadpool_jobs_cancelled_total {}", pool.cancelled());
+
+    header(
+        out,
+        "threadpool_queue_wait_seconds",
+        "histogram",
+        "Time jobs spent queued before a worker took them.",
+    );
+    histogram(out, "threadpool_queue_wait_seconds", "", pool.queue_wait());
+    header(
+        out,
+        "threadpool_job_duration_seconds",
+        "histogram",
+        "Time jobs took to run.",
+    );
+    histogram(out, "threadpool_job_duration_seconds", "", pool.run_time());
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn histogram(out: &mut String, name: &str, labels: &str, h: &Histogram) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    for (bound, count) in h.buckets() {
+        let le = match bound {
+            Some(bound) => bound.as_secs_f64().to_string(),
+            None => "+Inf".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"{}\"}} {}",
+            name, labels, sep, le, count
+        );
+    }
+    let braced = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    let _ = writeln!(out, "{}_sum{} {}", name, braced, h.sum().as_secs_f64());
+    let _ = writeln!(out, "{}_count{} {}", name, braced, h.count());
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LE: [&str; 20] = [
+        "0.00001", "0.000025", "0.00005", "0.0001", "0.00025", "0.0005", "0.001", "0.0025",
+        "0.005", "0.01", "0.025", "0.05", "0.1", "0.25", "0.5", "1", "2.5", "5", "10", "+Inf",
+    ];
+
+    // the lines of `text` starting with `prefix`
+    fn lines<'a>(text: &'a str, prefix: &str) -> Vec<&'a str> {
+        text.lines()
+            .filter(|line| line.starts_with(prefix))
+            .collect()
+    }
+
+    #[test]
+    fn counts_requests_by_route_and_status() {
+        let metrics = ServerMetrics::new();
+        metrics.record(Some("/users/:id"), StatusCode::Ok, Duration::from_millis(1));
+        metrics.record(Some("/users/:id"), StatusCode::Ok, Duration::from_millis(1));
+        metrics.record(
+            Some("/users/:id"),
+            StatusCode::NotFound,
+            Duration::from_millis(1),
+        );
+        metrics.record(None, StatusCode::BadRequest, Duration::from_millis(1));
+        let text = metrics.render(None);
+        assert_eq!(
+            lines(&text, "http_requests_total"),
+            [
+                "http_requests_total{route=\"/users/:id\",status=\"200\"} 2",
+                "http_requests_total{route=\"/users/:id\",status=\"404\"} 1",
+                "http_requests_total{route=\"unmatched\",status=\"400\"} 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn histogram_lines() {
+        let metrics = ServerMetrics::new();
+        for us in [5, 20, 20, 3_000_000, 60_000_000] {
+            metrics.record(Some("/"), StatusCode::Ok, Duration::from_micros(us));
+        }
+        let text = metrics.render(None);
+        let counts = [1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4, 5];
+        let mut expected: Vec<String> = LE
+            .iter()
+            .zip(counts)
+            .map(|(le, n)| {
+                format!(
+                    "http_request_duration_seconds_bucket{{route=\"/\",le=\"{}\"}} {}",
+                    le, n
+                )
+            })
+            .collect();
+        expected.push("http_request_duration_seconds_sum{route=\"/\"} 63.000045".to_string());
+        expected.push("http_request_duration_seconds_count{route=\"/\"} 5".to_string());
+        assert_eq!(lines(&text, "http_request_duration_seconds"), expected);
+        assert_eq!(
+            lines(&text, "# TYPE http_request_duration_seconds"),
+            ["# TYPE http_request_duration_seconds histogram"]
+        );
+    }
+
+    #[test]
+    fn unlabelled_histogram() {
+        let mut h = Histogram::default();
+        h.record(Duration::from_millis(2));
+        let mut out = String::new();
+        histogram(&mut out, "jobs", "", &h);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), LE.len() + 2);
+        assert_eq!(lines[0], "jobs_bucket{le=\"0.00001\"} 0");
+        assert_eq!(lines[7], "jobs_bucket{le=\"0.0025\"} 1");
+        assert_eq!(lines[19], "jobs_bucket{le=\"+Inf\"} 1");
+        assert_eq!(lines[20], "jobs_sum 0.002");
+        assert_eq!(lines[21], "jobs_count 1");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = ServerMetrics::new();
+        metrics.record(
+            Some("/a\"b\\c\nd"),
+            StatusCode::Ok,
+            Duration::from_millis(1),
+        );
+        let text = metrics.render(None);
+        assert_eq!(
+            lines(&text, "http_requests_total{"),
+            ["http_requests_total{route=\"/a\\\"b\\\\c\\nd\",status=\"200\"} 1"]
+        );
+        assert_eq!(
+            lines(&text, "http_request_duration_seconds_count"),
+            ["http_request_duration_seconds_count{route=\"/a\\\"b\\\\c\\nd\"} 1"]
+        );
+    }
+
+    #[test]
+    fn open_connections_gauge() {
+        let metrics = Arc::new(ServerMetrics::new());
+        let first = metrics.connection_opened();
+        let _second = metrics.connection_opened();
+        drop(first);
+        assert_eq!(metrics.open_connections(), 1);
+        assert_eq!(
+            lines(&metrics.render(None), "http_open_connections"),
+            ["http_open_connections 1"]
+        );
+    }
+
+    #[test]
+    fn pool_stats_are_included() {
+        let mut wait = Histogram::default();
+        wait.record(Duration::from_micros(40));
+        let pool = PoolStats {
+            queued: 3,
+            workers: Vec::new(),
+            completed: 10,
+            panicked: 1,
+            cancelled: 2,
+            wait,
+            run: Histogram::default(),
+        };
+        let text = ServerMetrics::new().render(Some(&pool));
+        for line in [
+            "threadpool_queued_jobs 3",
+            "threadpool_workers{state=\"busy\"} 0",
+            "threadpool_utilization 0",
+            "threadpool_jobs_completed_total 10",
+            "threadpool_jobs_panicked_total 1",
+            "threadpool_jobs_cancelled_total 2",
+            "threadpool_queue_wait_seconds_bucket{le=\"0.00005\"} 1",
+            "threadpool_queue_wait_seconds_count 1",
+            "threadpool_job_duration_seconds_bucket{le=\"+Inf\"} 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?}", line);
+        }
+        assert!(!ServerMetrics::new().render(None).contains("threadpool_"));
+    }
+}
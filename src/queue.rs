@@ -0,0 +1,875 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::{lock, Job};
+
+/// What `ThreadPool::execute` does when the job queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait until a worker frees up a slot.
+    Block,
+    /// Fail with `ExecuteError::QueueFull`.
+    Reject,
+    /// Throw away the oldest queued job to make room.
+    DropOldest,
+}
+
+/// How urgently a job should run, see `ThreadPool::execute_with_priority`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+// number of priority classes; a class is its Priority as an index
+const CLASSES: usize = 3;
+
+pub(crate) enum PushError<T> {
+    Full(T),
+    Closed(T),
+}
+
+/// What a worker got from `JobQueue::pop`.
+pub(crate) enum Pop {
+    /// A job and how long it was queued.
+    Job(Job, Duration),
+    /// Timed out or woken by `wake_all` without a job.
+    Idle,
+    /// Closed and drained, time to stop.
+    Closed,
+}
+
+// times an idle worker looks again before going to sleep
+const SPINS: u32 = 16;
+// how often, in pops, lower priority jobs are checked for having waited
+// past the aging limit while there's higher priority work around
+const SWEEP_EVERY: usize = 16;
+
+/// The queue shared between the pool and its workers.
+///
+/// Jobs are spread over a number of shards, one per worker slot, so
+/// submitting and picking up jobs doesn't all go through a single lock. A
+/// worker takes from its own shard first and steals from the others when
+/// that's empty. Jobs are handed out in order within a shard, but there's no
+/// strict order across the whole queue.
+///
+/// Higher priority jobs go first, except that a job that has waited longer
+/// than the aging limit is taken ahead of newer, more urgent ones so a
+/// steady stream of those can't starve it.
+///
+/// Closing the queue lets workers drain whatever is left and then stop.
+pub(crate) struct JobQueue {
+    shards: Box<[Shard]>,
+    // where the next push goes, round robin
+    next: AtomicUsize,
+    // jobs queued, counted before they land in a shard so capacity can be
+    // reserved up front
+    len: AtomicUsize,
+    // jobs sitting in the shards by class, so workers know which to look for
+    queued: [AtomicUsize; CLASSES],
+    aging: Duration,
+    pops: AtomicUsize,
+    cancelled: AtomicU64,
+    closed: AtomicBool,
+    // workers in pop that found nothing, whether spinning or asleep
+    idle: AtomicUsize,
+    // workers asleep in pop and pushers blocked on a full queue; the sleep
+    // lock is only taken when one of these is non-zero
+    sleeping: AtomicUsize,
+    blocked: AtomicUsize,
+    // bumped by wake_all
+    generation: AtomicU64,
+    sleep: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+}
+
+// on its own cache line so workers polling neighbouring shards don't slow
+// each other down
+#[repr(align(64))]
+struct Shard {
+    jobs: Mutex<[VecDeque<Entry>; CLASSES]>,
+}
+
+struct Entry {
+    job: Job,
+    queued_at: Instant,
+    token: Option<CancellationToken>,
+}
+
+impl Entry {
+    fn is_cancelled(&self) -> bool {
+        self.token.as_ref().is_some_and(|t| t.is_cancelled())
+    }
+}
+
+impl JobQueue {
+    pub fn new(
+        shards: usize,
+        capacity: Option<usize>,
+        policy: QueuePolicy,
+        aging: Duration,
+    ) -> JobQueue {
+        assert!(capacity != Some(0));
+        JobQueue {
+            shards: (0..shards.max(1))
+                .map(|_| Shard {
+                    jobs: Mutex::default(),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            queued: Default::default(),
+            aging,
+            pops: AtomicUsize::new(0),
+            cancelled: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            idle: AtomicUsize::new(0),
+            sleeping: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            sleep: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Queues a job, applying the queue policy if we're at capacity.
+    pub fn push(
+        &self,
+        job: Job,
+        priority: Priority,
+        token: Option<CancellationToken>,
+    ) -> Result<(), PushError<Job>> {
+        loop {
+            if self.is_closed() {
+                return Err(PushError::Closed(job));
+            }
+            if self.reserve() {
+                break;
+            }
+            if self.purge_cancelled() > 0 {
+                continue;
+            }
+            match self.policy {
+                QueuePolicy::Block => self.wait_for_room(),
+                QueuePolicy::Reject => return Err(PushError::Full(job)),
+                QueuePolicy::DropOldest => {
+                    // swap the oldest job for ours, the count stays the same
+                    if self.take_oldest() {
+                        self.insert(job, priority, token);
+                        return Ok(());
+                    }
+                    // whatever was there got picked up meanwhile
+                    thread::yield_now();
+                }
+            }
+        }
+        self.insert(job, priority, token);
+        Ok(())
+    }
+
+    /// Queues the job made by `make` if there's room, without blocking or
+    /// dropping anything. `f` is handed back otherwise.
+    pub fn try_push<F, M>(
+        &self,
+        f: F,
+        make: M,
+        priority: Priority,
+        token: Option<CancellationToken>,
+    ) -> Result<(), PushError<F>>
+    where
+        M: FnOnce(F) -> Job,
+    {
+        if self.is_closed() {
+            return Err(PushError::Closed(f));
+        }
+        // clearing out cancelled jobs may make room
+        let room = self.reserve() || (self.purge_cancelled() > 0 && self.reserve());
+        if !room {
+            return Err(PushError::Full(f));
+        }
+        self.insert(make(f), priority, token);
+        Ok(())
+    }
+
+    /// Blocks until a job is available, `timeout` passes or `wake_all` is
+    /// called. `home` is the worker's id, which picks the shard it looks at
+    /// first. `seen` is the last generation the caller acted on: if
+    /// `wake_all` has been called since, this returns `Pop::Idle` instead of
+    /// going to sleep. Returns `Pop::Closed` once the queue has been closed
+    /// and emptied.
+    pub fn pop(&self, home: usize, timeout: Option<Duration>, seen: u64) -> Pop {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut spins = 0;
+        let mut idle = None;
+        loop {
+            if let Some(entry) = self.steal(home) {
+                self.taken(1);
+                if entry.is_cancelled() {
+                    self.cancelled.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                return Pop::Job(entry.job, entry.queued_at.elapsed());
+            }
+            if idle.is_none() {
+                idle = Some(Counted::new(&self.idle));
+            }
+            if self.len.load(Ordering::SeqCst) > 0 {
+                // counted but not in a shard yet, or just taken by someone
+                // else; either way it won't be long
+                thread::yield_now();
+                continue;
+            }
+            if self.is_closed() {
+                return Pop::Closed;
+            }
+            // under a steady stream of small jobs the next one is usually
+            // only moments away, and going to sleep means the pusher has to
+            // take the sleep lock to wake us
+            if spins < SPINS {
+                spins += 1;
+                thread::yield_now();
+                continue;
+            }
+
+            let mut sleep = lock(&self.sleep);
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            // a push that didn't see us as sleeping must show up here
+            let ready = self.len.load(Ordering::SeqCst) > 0
+                || self.is_closed()
+                || self.generation.load(Ordering::SeqCst) != seen;
+            if !ready {
+                sleep = match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(wait) if !wait.is_zero() => {
+                            self.not_empty
+                                .wait_timeout(sleep, wait)
+                                .unwrap_or_else(PoisonError::into_inner)
+                                .0
+                        }
+                        _ => {
+                            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                            return Pop::Idle;
+                        }
+                    },
+                    None => self
+                        .not_empty
+                        .wait(sleep)
+                        .unwrap_or_else(PoisonError::into_inner),
+                };
+            }
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            drop(sleep);
+
+            if self.len.load(Ordering::SeqCst) > 0 || self.is_closed() {
+                continue;
+            }
+            if self.generation.load(Ordering::SeqCst) != seen {
+                return Pop::Idle;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Pop::Idle;
+            }
+        }
+    }
+
+    /// Makes every worker waiting in `pop` return, so they can check
+    /// whether they're still wanted.
+    pub fn wake_all(&self) {
+        let _sleep = lock(&self.sleep);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.not_empty.notify_all();
+    }
+
+    /// Goes up on every `wake_all`.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting jobs. Anything already queued is still handed out.
+    pub fn close(&self) {
+        let _sleep = lock(&self.sleep);
+        self.closed.store(true, Ordering::SeqCst);
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// Queued jobs that no idle worker is about to pick up.
+    pub fn backlog(&self) -> usize {
+        self.len().saturating_sub(self.idle.load(Ordering::SeqCst))
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Jobs dropped because their token was cancelled before they started.
+    pub fn cancelled(&self) -> u64 {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Counts one more job if there's room for it.
+    fn reserve(&self) -> bool {
+        match self.capacity {
+            None => {
+                self.len.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(cap) => self
+                .len
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                    (len < cap).then(|| len + 1)
+                })
+                .is_ok(),
+        }
+    }
+
+    /// Puts an already counted job in the next shard and wakes a worker if
+    /// any are asleep.
+    fn insert(&self, job: Job, priority: Priority, token: Option<CancellationToken>) {
+        let class = priority as usize;
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        {
+            let mut jobs = lock(&self.shards[i].jobs);
+            jobs[class].push_back(Entry {
+                job,
+                queued_at: Instant::now(),
+                token,
+            });
+            self.queued[class].fetch_add(1, Ordering::SeqCst);
+        }
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _sleep = lock(&self.sleep);
+            self.not_empty.notify_one();
+        }
+    }
+
+    /// Takes the most urgent job, looking in shard `home` first.
+    fn steal(&self, home: usize) -> Option<Entry> {
+        if self.starving()
+            && self
+                .pops
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(SWEEP_EVERY)
+        {
+            if let Some(job) = self.take_aged() {
+                return Some(job);
+            }
+        }
+        let n = self.shards.len();
+        for class in 0..CLASSES {
+            if self.queued[class].load(Ordering::SeqCst) == 0 {
+                continue;
+            }
+            for i in 0..n {
+                if let Some(job) = self.take((home + i) % n, class) {
+                    return Some(job);
+                }
+            }
+        }
+        None
+    }
+
+    /// True if there are jobs queued behind more urgent ones.
+    fn starving(&self) -> bool {
+        let mut classes = self.queued.iter().map(|n| n.load(Ordering::Relaxed) > 0);
+        classes.position(|queued| queued).is_some() && classes.any(|queued| queued)
+    }
+
+    /// Takes the oldest job that's been queued past the aging limit from
+    /// behind more urgent work, if there is one.
+    fn take_aged(&self) -> Option<Entry> {
+        let now = Instant::now();
+        let (shard, class, _) = (1..CLASSES)
+            .filter_map(|class| {
+                let (shard, queued_at) = self.oldest(class)?;
+                Some((shard, class, queued_at))
+            })
+            .filter(|&(_, _, queued_at)| now.duration_since(queued_at) >= self.aging)
+            .min_by_key(|&(_, _, queued_at)| queued_at)?;
+        self.take(shard, class)
+    }
+
+    /// Throws away the job that's been queued longest from the lowest
+    /// priority class that has any, if there is one.
+    fn take_oldest(&self) -> bool {
+        (0..CLASSES).rev().any(|class| {
+            self.queued[class].load(Ordering::SeqCst) > 0
+                && self
+                    .oldest(class)
+                    .and_then(|(shard, _)| self.take(shard, class))
+                    .is_some()
+        })
+    }
+
+    /// The shard holding the oldest job of `class`, and when it was queued.
+    fn oldest(&self, class: usize) -> Option<(usize, Instant)> {
+        self.shards
+            .iter()
+            .enumerate()
+            .filter_map(|(i, shard)| Some((i, lock(&shard.jobs)[class].front()?.queued_at)))
+            .min_by_key(|&(_, queued_at)| queued_at)
+    }
+
+    fn take(&self, shard: usize, class: usize) -> Option<Entry> {
+        let entry = lock(&self.shards[shard].jobs)[class].pop_front()?;
+        self.queued[class].fetch_sub(1, Ordering::SeqCst);
+        Some(entry)
+    }
+
+    /// Drops every queued job whose token has been cancelled, returns how
+    /// many went.
+    fn purge_cancelled(&self) -> usize {
+        let mut purged = Vec::new();
+        for shard in self.shards.iter() {
+            let mut jobs = lock(&shard.jobs);
+            for (class, jobs) in jobs.iter_mut().enumerate() {
+                let before = purged.len();
+                for entry in mem::take(jobs) {
+                    if entry.is_cancelled() {
+                        purged.push(entry);
+                    } else {
+                        jobs.push_back(entry);
+                    }
+                }
+                self.queued[class].fetch_sub(purged.len() - before, Ordering::SeqCst);
+            }
+        }
+        if !purged.is_empty() {
+            self.taken(purged.len());
+            self.cancelled
+                .fetch_add(purged.len() as u64, Ordering::Relaxed);
+        }
+        // dropped out here, not under a shard lock
+        purged.len()
+    }
+
+    /// Uncounts `n` jobs that have left the shards, letting a blocked push
+    /// through.
+    fn taken(&self, n: usize) {
+        self.len.fetch_sub(n, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _sleep = lock(&self.sleep);
+            if n == 1 {
+                self.not_full.notify_one();
+            } else {
+                self.not_full.notify_all();
+            }
+        }
+    }
+
+    fn wait_for_room(&self) {
+        let sleep = lock(&self.sleep);
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        let full = self
+            .capacity
+            .is_some_and(|cap| self.len.load(Ordering::SeqCst) >= cap);
+        let sleep = if full && !self.is_closed() {
+            self.not_full
+                .wait(sleep)
+                .unwrap_or_else(PoisonError::into_inner)
+        } else {
+            sleep
+        };
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+        drop(sleep);
+    }
+}
+
+// Counts a worker in for as long as it's held.
+struct Counted<'a>(&'a AtomicUsize);
+
+impl<'a> Counted<'a> {
+    fn new(count: &'a AtomicUsize) -> Counted<'a> {
+        count.fetch_add(1, Ordering::SeqCst);
+        Counted(count)
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Log = Arc<Mutex<Vec<u32>>>;
+
+    fn job(log: &Log, n: u32) -> Job {
+        let log = log.clone();
+        Box::new(move || log.lock().unwrap().push(n))
+    }
+
+    fn queue(shards: usize, capacity: Option<usize>, policy: QueuePolicy) -> JobQueue {
+        JobQueue::new(shards, capacity, policy, Duration::from_secs(3600))
+    }
+
+    fn push(queue: &JobQueue, log: &Log, n: u32, priority: Priority) {
+        assert!(queue.push(job(log, n), priority, None).is_ok());
+    }
+
+    // pops and runs whatever is queued right now
+    fn drain(queue: &JobQueue) -> usize {
+        let mut ran = 0;
+        while queue.len() > 0 {
+            match queue.pop(0, Some(Duration::ZERO), 0) {
+                Pop::Job(job, _) => {
+                    job.call_box();
+                    ran += 1;
+                }
+                Pop::Idle => {}
+                Pop::Closed => break,
+            }
+        }
+        ran
+    }
+
+    fn queue_with(log: &Log, n: u32) -> JobQueue {
+        let queue = queue(1, None, QueuePolicy::Block);
+        for i in 0..n {
+            push(&queue, log, i, Priority::Normal);
+        }
+        queue
+    }
+
+    fn logged(log: &Log) -> Vec<u32> {
+        log.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn higher_priority_first() {
+        let log = Log::default();
+        let queue = queue(3, None, QueuePolicy::Block);
+        push(&queue, &log, 1, Priority::Low);
+        push(&queue, &log, 2, Priority::Normal);
+        push(&queue, &log, 3, Priority::High);
+        push(&queue, &log, 4, Priority::Normal);
+        push(&queue, &log, 5, Priority::High);
+        assert_eq!(drain(&queue), 5);
+        // no order across shards within a class, only between classes
+        let mut ran = logged(&log);
+        ran[..2].sort_unstable();
+        ran[2..4].sort_unstable();
+        assert_eq!(ran, [3, 5, 2, 4, 1]);
+    }
+
+    #[test]
+    fn fifo_within_a_shard() {
+        let log = Log::default();
+        let queue = queue(1, None, QueuePolicy::Block);
+        for n in 0..10 {
+            push(&queue, &log, n, Priority::Normal);
+        }
+        drain(&queue);
+        assert_eq!(logged(&log), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn aged_jobs_jump_the_queue() {
+        let log = Log::default();
+        let queue = JobQueue::new(1, None, QueuePolicy::Block, Duration::from_millis(10));
+        push(&queue, &log, 1, Priority::Low);
+        thread::sleep(Duration::from_millis(20));
+        for n in 2..6 {
+            push(&queue, &log, n, Priority::High);
+        }
+        drain(&queue);
+        assert_eq!(logged(&log), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn young_jobs_wait_their_turn() {
+        let log = Log::default();
+        let queue = queue(1, None, QueuePolicy::Block);
+        push(&queue, &log, 1, Priority::Low);
+        for n in 2..6 {
+            push(&queue, &log, n, Priority::High);
+        }
+        drain(&queue);
+        assert_eq!(logged(&log), [2, 3, 4, 5, 1]);
+    }
+
+    #[test]
+    fn cancelled_jobs_are_skipped() {
+        let log = Log::default();
+        let queue = queue(2, None, QueuePolicy::Block);
+        let token = CancellationToken::new();
+        assert!(queue
+            .push(job(&log, 1), Priority::Normal, Some(token.clone()))
+            .is_ok());
+        push(&queue, &log, 2, Priority::Normal);
+        token.cancel();
+        assert_eq!(drain(&queue), 1);
+        assert_eq!(logged(&log), [2]);
+        assert_eq!(queue.cancelled(), 1);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn cancelled_jobs_make_room() {
+        let log = Log::default();
+        let queue = queue(2, Some(2), QueuePolicy::Reject);
+        let token = CancellationToken::new();
+        for n in 0..2 {
+            assert!(queue
+                .push(job(&log, n), Priority::Normal, Some(token.clone()))
+                .is_ok());
+        }
+        assert!(matches!(
+            queue.push(job(&log, 2), Priority::Normal, None),
+            Err(PushError::Full(_))
+        ));
+        token.cancel();
+        push(&queue, &log, 3, Priority::Normal);
+        assert_eq!(queue.cancelled(), 2);
+        assert!(queue
+            .try_push(4, |n| job(&log, n), Priority::Normal, None)
+            .is_ok());
+        assert_eq!(queue.len(), 2);
+        drain(&queue);
+        assert_eq!(logged(&log), [3, 4]);
+    }
+
+    #[test]
+    fn reject_when_full() {
+        let log = Log::default();
+        let queue = queue(2, Some(2), QueuePolicy::Reject);
+        push(&queue, &log, 1, Priority::Normal);
+        push(&queue, &log, 2, Priority::Normal);
+        assert!(matches!(
+            queue.push(job(&log, 3), Priority::High, None),
+            Err(PushError::Full(_))
+        ));
+        assert!(matches!(
+            queue.try_push(4, |n| job(&log, n), Priority::High, None),
+            Err(PushError::Full(4))
+        ));
+        assert_eq!(queue.len(), 2);
+        drain(&queue);
+        assert_eq!(logged(&log), [1, 2]);
+    }
+
+    #[test]
+    fn try_push_never_drops() {
+        let log = Log::default();
+        let queue = queue(1, Some(1), QueuePolicy::DropOldest);
+        push(&queue, &log, 1, Priority::Normal);
+        assert!(matches!(
+            queue.try_push(2, |n| job(&log, n), Priority::Normal, None),
+            Err(PushError::Full(2))
+        ));
+        drain(&queue);
+        assert_eq!(logged(&log), [1]);
+    }
+
+    #[test]
+    fn drop_oldest_when_full() {
+        let log = Log::default();
+        let queue = queue(2, Some(2), QueuePolicy::DropOldest);
+        for n in 1..=4 {
+            push(&queue, &log, n, Priority::Normal);
+        }
+        assert_eq!(queue.len(), 2);
+        drain(&queue);
+        assert_eq!(logged(&log), [3, 4]);
+    }
+
+    #[test]
+    fn drop_oldest_takes_the_lowest_priority() {
+        let log = Log::default();
+        let queue = queue(2, Some(3), QueuePolicy::DropOldest);
+        push(&queue, &log, 1, Priority::High);
+        push(&queue, &log, 2, Priority::Low);
+        push(&queue, &log, 3, Priority::Normal);
+        push(&queue, &log, 4, Priority::High);
+        drain(&queue);
+        assert_eq!(logged(&log), [1, 4, 3]);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let log = Log::default();
+        let queue = Arc::new(queue(1, Some(1), QueuePolicy::Block));
+        push(&queue, &log, 1, Priority::Normal);
+        let pusher = {
+            let (queue, log) = (queue.clone(), log.clone());
+            thread::spawn(move || push(&queue, &log, 2, Priority::Normal))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pusher.is_finished());
+        assert_eq!(queue.len(), 1);
+
+        match queue.pop(0, None, 0) {
+            Pop::Job(job, _) => job.call_box(),
+            _ => panic!("expected a job"),
+        }
+        pusher.join().unwrap();
+        drain(&queue);
+        assert_eq!(logged(&log), [1, 2]);
+    }
+
+    #[test]
+    fn close_lets_blocked_pushers_go() {
+        let log = Log::default();
+        let queue = Arc::new(queue(1, Some(1), QueuePolicy::Block));
+        push(&queue, &log, 1, Priority::Normal);
+        let pusher = {
+            let (queue, log) = (queue.clone(), log.clone());
+            thread::spawn(move || queue.push(job(&log, 2), Priority::Normal, None).is_ok())
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert!(!pusher.join().unwrap());
+    }
+
+    #[test]
+    fn close_and_drain() {
+        let log = Log::default();
+        let queue = Arc::new(queue(2, None, QueuePolicy::Block));
+        let waiting = {
+            let queue = queue.clone();
+            thread::spawn(move || matches!(queue.pop(0, None, 0), Pop::Closed))
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert!(waiting.join().unwrap());
+
+        let queue = queue_with(&log, 3);
+        queue.close();
+        assert!(matches!(
+            queue.push(job(&log, 9), Priority::Normal, None),
+            Err(PushError::Closed(_))
+        ));
+        // what was already queued still comes out, then Closed
+        for _ in 0..3 {
+            match queue.pop(1, None, 0) {
+                Pop::Job(job, _) => job.call_box(),
+                _ => panic!("expected a job"),
+            }
+        }
+        assert!(matches!(queue.pop(1, None, 0), Pop::Closed));
+        assert_eq!(logged(&log), [0, 1, 2]);
+    }
+
+    #[test]
+    fn wake_all_returns_idle() {
+        let queue = Arc::new(queue(1, None, QueuePolicy::Block));
+        let waiting = {
+            let queue = queue.clone();
+            thread::spawn(move || matches!(queue.pop(0, None, 0), Pop::Idle))
+        };
+        while queue.sleeping.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        queue.wake_all();
+        assert!(waiting.join().unwrap());
+        assert_eq!(queue.generation(), 1);
+    }
+
+    #[test]
+    fn missed_wake_all_returns_idle() {
+        let queue = queue(1, None, QueuePolicy::Block);
+        queue.wake_all();
+        // a worker that comes round after the wake-up mustn't sleep through it
+        assert!(matches!(queue.pop(0, None, 0), Pop::Idle));
+    }
+
+    #[test]
+    fn backlog_leaves_out_idle_workers() {
+        let log = Log::default();
+        let queue = Arc::new(queue(2, None, QueuePolicy::Block));
+        push(&queue, &log, 1, Priority::Normal);
+        assert_eq!(queue.backlog(), 1);
+        drain(&queue);
+
+        let waiting = {
+            let queue = queue.clone();
+            thread::spawn(move || matches!(queue.pop(0, None, 0), Pop::Job(..)))
+        };
+        // counted as soon as it finds nothing, spinning or not
+        while queue.idle.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        push(&queue, &log, 2, Priority::Normal);
+        assert_eq!(queue.backlog(), 0);
+        assert!(waiting.join().unwrap());
+    }
+
+    #[test]
+    fn pop_times_out() {
+        let queue = queue(1, None, QueuePolicy::Block);
+        let start = Instant::now();
+        assert!(matches!(
+            queue.pop(0, Some(Duration::from_millis(20)), 0),
+            Pop::Idle
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn no_jobs_lost_across_threads() {
+        let log = Log::default();
+        let queue = Arc::new(queue(4, Some(8), QueuePolicy::Block));
+        let workers: Vec<_> = (0..4)
+            .map(|home| {
+                let queue = queue.clone();
+                thread::spawn(move || loop {
+                    match queue.pop(home, None, 0) {
+                        Pop::Job(job, _) => job.call_box(),
+                        Pop::Idle => {}
+                        Pop::Closed => return,
+                    }
+                })
+            })
+            .collect();
+        let pushers: Vec<_> = (0..4)
+            .map(|p| {
+                let (queue, log) = (queue.clone(), log.clone());
+                thread::spawn(move || {
+                    for n in 0..500 {
+                        let priority = [Priority::High, Priority::Normal, Priority::Low][n % 3];
+                        push(&queue, &log, p * 1000 + n as u32, priority);
+                    }
+                })
+            })
+            .collect();
+        for pusher in pushers {
+            pusher.join().unwrap();
+        }
+        queue.close();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let mut ran = logged(&log);
+        ran.sort_unstable();
+        let expected: Vec<u32> = (0..4)
+            .flat_map(|p| (0..500).map(move |n| p * 1000 + n))
+            .collect();
+        assert_eq!(ran, expected);
+        assert_eq!(queue.len(), 0);
+    }
+}
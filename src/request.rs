@@ -0,0 +1,803 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str;
+
+use crate::chunked::ChunkedDecoder;
+use crate::headers::Headers;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    /// Any other syntactically valid method.
+    Other(String),
+}
+
+impl Method {
+    fn parse(s: &str) -> Result<Method, ParseError> {
+        let method = match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            _ if is_token(s) => Method::Other(s.to_string()),
+            _ => return Err(ParseError::InvalidMethod),
+        };
+        Ok(method)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Other(s) => s,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    fn parse(s: &str) -> Result<Version, ParseError> {
+        match s {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ if s.starts_with("HTTP/") => Err(ParseError::UnsupportedVersion),
+            _ => Err(ParseError::InvalidRequestLine),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A parsed HTTP request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    method: Method,
+    target: String,
+    version: Version,
+    headers: Headers,
+    body: Vec<u8>,
+    trailers: Headers,
+    // filled in by the router from the matched route pattern
+    params: Vec<(String, String)>,
+    route: Option<String>,
+}
+
+impl Request {
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// The request target exactly as sent, including any query string.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(i) => &self.target[..i],
+            None => &self.target,
+        }
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.target.find('?').map(|i| &self.target[i + 1..])
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body, already de-chunked if it was sent chunked.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Trailer fields sent after a chunked body.
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    /// A parameter captured by the route that matched, e.g. `id` for
+    /// `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// The pattern of the route that matched, e.g. `/users/:id`. Set by
+    /// `Router::handle`.
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    pub(crate) fn set_route(&mut self, pattern: &str, params: Vec<(String, String)>) {
+        self.route = Some(pattern.to_string());
+        self.params = params;
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    /// The connection closed part way through a request.
+    UnexpectedEof,
+    InvalidRequestLine,
+    InvalidMethod,
+    InvalidTarget,
+    UnsupportedVersion,
+    InvalidHeader,
+    InvalidContentLength,
+    UnsupportedTransferEncoding,
+    InvalidChunk,
+    RequestLineTooLong,
+    TooManyHeaders,
+    HeadersTooLarge,
+    BodyTooLarge,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "i/o error: {}", e),
+            ParseError::UnexpectedEof => f.write_str("connection closed mid-request"),
+            ParseError::InvalidRequestLine => f.write_str("malformed request line"),
+            ParseError::InvalidMethod => f.write_str("invalid method"),
+            ParseError::InvalidTarget => f.write_str("invalid request target"),
+            ParseError::UnsupportedVersion => f.write_str("unsupported HTTP version"),
+            ParseError::InvalidHeader => f.write_str("malformed header field"),
+            ParseError::InvalidContentLength => f.write_str("invalid Content-Length"),
+            ParseError::UnsupportedTransferEncoding => f.write_str("unsupported Transfer-Encoding"),
+            ParseError::InvalidChunk => f.write_str("malformed chunked body"),
+            ParseError::RequestLineTooLong => f.write_str("request line too long"),
+            ParseError::TooManyHeaders => f.write_str("too many header fields"),
+            ParseError::HeadersTooLarge => f.write_str("header section too large"),
+            ParseError::BodyTooLarge => f.write_str("request body too large"),
+        }
+    }
+}
+
+impl error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+/// Caps on how big a request the parser will accept.
+///
+/// Requests over a limit are rejected as soon as that's clear, rather than
+/// buffered in full first.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Longest request line, in bytes.
+    pub request_line: usize,
+    /// Most header fields in one request.
+    pub headers: usize,
+    /// Largest header section (everything after the request line), in
+    /// bytes. Also applies to chunked trailers.
+    pub header_bytes: usize,
+    /// Largest body, in bytes, after any chunked decoding.
+    pub body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            request_line: 8 * 1024,
+            headers: 100,
+            header_bytes: 64 * 1024,
+            body: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Incremental request parser.
+///
+/// Bytes can be fed in however they arrive off the socket; `parse` hands
+/// back a request once a complete one is buffered and keeps whatever follows
+/// it for the next call.
+#[derive(Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+    // how far we've already searched for the end of the head
+    scanned: usize,
+    // head already parsed, waiting on the body
+    pending: Option<(Request, BodyState)>,
+    limits: Limits,
+}
+
+enum BodyState {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    pub fn with_limits(limits: Limits) -> Parser {
+        Parser {
+            limits,
+            ..Parser::default()
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// True if there's nothing buffered, i.e. we're between requests.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty() && self.pending.is_none()
+    }
+
+    /// True once a request's head has been parsed and its body is still
+    /// coming in.
+    pub fn in_body(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// The line of the request currently being read, as much of it as has
+    /// arrived. For logging a request that failed part way.
+    pub(crate) fn request_line(&self) -> Option<String> {
+        if let Some((request, _)) = &self.pending {
+            let (method, target) = (request.method(), request.target());
+            return Some(format!("{} {} {}", method, target, request.version()));
+        }
+        let end = self
+            .buf
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .unwrap_or(self.buf.len())
+            .min(self.limits.request_line);
+        if end == 0 {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.buf[..end]).into_owned())
+    }
+
+    /// Tries to parse one request out of the buffered bytes.
+    ///
+    /// Returns `Ok(None)` if more input is needed.
+    pub fn parse(&mut self) -> Result<Option<Request>, ParseError> {
+        if self.pending.is_none() {
+            // clients may send stray CRLFs between requests, skip them.
+            let blank = self
+                .buf
+                .iter()
+                .take_while(|&&b| b == b'\r' || b == b'\n')
+                .count();
+            if blank > 0 {
+                self.buf.drain(..blank);
+                self.scanned = 0;
+            }
+
+            let end = self.find_head_end();
+            self.check_head_size(end.unwrap_or(self.buf.len()))?;
+            let end = match end {
+                Some(end) => end,
+                None => return Ok(None),
+            };
+            let request = parse_head(&self.buf[..end], &self.limits)?;
+            let body = body_state(&request.headers, &self.limits)?;
+            self.buf.drain(..end + 4);
+            self.scanned = 0;
+            self.pending = Some((request, body));
+        }
+
+        let done = match &mut self.pending {
+            Some((_, BodyState::Length(length))) => self.buf.len() >= *length,
+            Some((_, BodyState::Chunked(decoder))) => decoder.decode(&mut self.buf)?,
+            None => unreachable!(),
+        };
+        if !done {
+            return Ok(None);
+        }
+        let request = match self.pending.take() {
+            Some((mut request, BodyState::Length(length))) => {
+                request.body = self.buf.drain(..length).collect();
+                request
+            }
+            Some((mut request, BodyState::Chunked(decoder))) => {
+                let (body, trailers) = decoder.into_parts();
+                request.body = body;
+                request.trailers = trailers;
+                request
+            }
+            None => unreachable!(),
+        };
+        Ok(Some(request))
+    }
+
+    /// Reads from `reader` until a whole request is available.
+    ///
+    /// Returns `Ok(None)` if the reader hits EOF cleanly between requests.
+    pub fn read_request<R: Read>(&mut self, reader: &mut R) -> Result<Option<Request>, ParseError> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(request) = self.parse()? {
+                return Ok(Some(request));
+            }
+            let n = match reader.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                if self.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ParseError::UnexpectedEof);
+            }
+            self.feed(&chunk[..n]);
+        }
+    }
+
+    /// Checks the first `len` bytes of the head (all of it, or as much as
+    /// has arrived) against the limits.
+    fn check_head_size(&self, len: usize) -> Result<(), ParseError> {
+        let head = &self.buf[..len];
+        let line = match head.windows(2).position(|w| w == b"\r\n") {
+            Some(line) => line,
+            None if len > self.limits.request_line => return Err(ParseError::RequestLineTooLong),
+            None => return Ok(()),
+        };
+        if line > self.limits.request_line {
+            return Err(ParseError::RequestLineTooLong);
+        }
+        if len - line > self.limits.header_bytes {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        Ok(())
+    }
+
+    fn find_head_end(&mut self) -> Option<usize> {
+        let start = self.scanned.saturating_sub(3);
+        let found = self.buf[start..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| start + i);
+        if found.is_none() {
+            self.scanned = self.buf.len();
+        }
+        found
+    }
+}
+
+fn parse_head(head: &[u8], limits: &Limits) -> Result<Request, ParseError> {
+    let head = str::from_utf8(head).map_err(|_| ParseError::InvalidHeader)?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::InvalidRequestLine),
+    };
+    let method = Method::parse(method)?;
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::InvalidTarget);
+    }
+    let version = Version::parse(version)?;
+
+    let mut headers = Headers::new();
+    for line in lines {
+        if headers.len() == limits.headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let colon = line.find(':').ok_or(ParseError::InvalidHeader)?;
+        let name = &line[..colon];
+        // no whitespace allowed before the colon, and obsolete line folding
+        // (a line starting with whitespace) is rejected outright.
+        if !is_token(name) {
+            return Err(ParseError::InvalidHeader);
+        }
+        let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+        if !is_field_value(value) {
+            return Err(ParseError::InvalidHeader);
+        }
+        headers.append(name, value);
+    }
+
+    Ok(Request {
+        method,
+        target: target.to_string(),
+        version,
+        headers,
+        body: Vec::new(),
+        trailers: Headers::new(),
+        params: Vec::new(),
+        route: None,
+    })
+}
+
+fn body_state(headers: &Headers, limits: &Limits) -> Result<BodyState, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        // Both framings at once is a classic request smuggling trick.
+        if headers.contains("Content-Length") {
+            return Err(ParseError::InvalidContentLength);
+        }
+        // chunked is the only coding we understand, and it has to be last
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect();
+        if codings.len() != 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        let decoder = ChunkedDecoder::new(limits.body, limits.header_bytes);
+        return Ok(BodyState::Chunked(decoder));
+    }
+    let length = body_length(headers)?;
+    if length > limits.body {
+        return Err(ParseError::BodyTooLarge);
+    }
+    Ok(BodyState::Length(length))
+}
+
+fn body_length(headers: &Headers) -> Result<usize, ParseError> {
+    let mut lengths = headers.get_all("Content-Length");
+    let length = match lengths.next() {
+        Some(v) => v,
+        None => return Ok(0),
+    };
+    // duplicates are only ok if they all agree
+    if lengths.any(|other| other != length) {
+        return Err(ParseError::InvalidContentLength);
+    }
+    if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::InvalidContentLength);
+    }
+    length.parse().map_err(|_| ParseError::InvalidContentLength)
+}
+
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// A bare CR or LF in a value could be taken as a line break by something
+/// else along the way and smuggle in another header, so those (and NUL) are
+/// refused.
+pub(crate) fn is_field_value(s: &str) -> bool {
+    !s.bytes().any(|b| b == b'\r' || b == b'\n' || b == b'\0')
+}
+
+/// Decodes `%XX` escapes, returning `None` for bad escapes or if the result
+/// isn't valid UTF-8.
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut Parser) -> Vec<Request> {
+        let mut requests = Vec::new();
+        while let Some(request) = parser.parse().unwrap() {
+            requests.push(request);
+        }
+        requests
+    }
+
+    fn parse_one(data: &[u8]) -> Result<Option<Request>, ParseError> {
+        let mut parser = Parser::new();
+        parser.feed(data);
+        parser.parse()
+    }
+
+    fn small_limits() -> Limits {
+        Limits {
+            request_line: 32,
+            headers: 2,
+            header_bytes: 64,
+            body: 8,
+        }
+    }
+
+    #[test]
+    fn request_split_across_reads() {
+        let data = b"POST /submit?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let mut parser = Parser::new();
+        for (i, b) in data.iter().enumerate() {
+            parser.feed(&[*b]);
+            let parsed = parser.parse().unwrap();
+            if i + 1 < data.len() {
+                assert!(parsed.is_none(), "complete after {} bytes", i + 1);
+                continue;
+            }
+            let request = parsed.unwrap();
+            assert_eq!(request.method(), &Method::Post);
+            assert_eq!(request.path(), "/submit");
+            assert_eq!(request.query(), Some("x=1"));
+            assert_eq!(request.version(), Version::Http11);
+            assert_eq!(request.header("host"), Some("a"));
+            assert_eq!(request.body(), b"hello");
+        }
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn pipelined_requests_in_one_buffer() {
+        let mut parser = Parser::new();
+        parser.feed(
+            b"GET /a HTTP/1.1\r\n\r\n\
+              POST /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+              GET /c HTTP/1.0\r\n\r\nGET /d",
+        );
+        let requests = parse_all(&mut parser);
+        let paths: Vec<&str> = requests.iter().map(|r| r.path()).collect();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+        assert_eq!(requests[1].body(), b"abc");
+        assert_eq!(requests[2].version(), Version::Http10);
+        // the start of the next one stays buffered
+        assert!(!parser.is_empty());
+        parser.feed(b" HTTP/1.1\r\n\r\n");
+        assert_eq!(parser.parse().unwrap().unwrap().path(), "/d");
+    }
+
+    #[test]
+    fn stray_crlfs_between_requests() {
+        let mut parser = Parser::new();
+        parser.feed(b"\r\n\r\nGET /a HTTP/1.1\r\n\r\n\r\n\nGET /b HTTP/1.1\r\n\r\n\r\n");
+        let requests = parse_all(&mut parser);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].path(), "/b");
+        assert!(parser.is_empty());
+    }
+
+    #[test]
+    fn chunked_body_and_trailers() {
+        let request = parse_one(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nChecksum: xyz\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.body(), b"abcde");
+        assert_eq!(request.trailers().get("checksum"), Some("xyz"));
+    }
+
+    #[test]
+    fn duplicate_content_length() {
+        let request =
+            parse_one(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi")
+                .unwrap()
+                .unwrap();
+        assert_eq!(request.body(), b"hi");
+
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nhi!";
+        assert!(matches!(
+            parse_one(conflicting),
+            Err(ParseError::InvalidContentLength)
+        ));
+        for bad in [&b"-1"[..], b"+2", b"0x2", b"", b"1 2"] {
+            let mut data = b"POST / HTTP/1.1\r\nContent-Length: ".to_vec();
+            data.extend_from_slice(bad);
+            data.extend_from_slice(b"\r\n\r\n");
+            assert!(matches!(
+                parse_one(&data),
+                Err(ParseError::InvalidContentLength)
+            ));
+        }
+    }
+
+    #[test]
+    fn transfer_encoding_with_content_length() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        assert!(matches!(
+            parse_one(data),
+            Err(ParseError::InvalidContentLength)
+        ));
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert!(matches!(
+            parse_one(data),
+            Err(ParseError::UnsupportedTransferEncoding)
+        ));
+    }
+
+    #[test]
+    fn control_characters_in_header_values() {
+        let data = b"GET / HTTP/1.1\r\nX: a\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(matches!(parse_one(data), Err(ParseError::InvalidHeader)));
+        let data = b"GET / HTTP/1.1\r\nX: a\rb\r\n\r\n";
+        assert!(matches!(parse_one(data), Err(ParseError::InvalidHeader)));
+        let data = b"GET / HTTP/1.1\r\nX: a\0b\r\n\r\n";
+        assert!(matches!(parse_one(data), Err(ParseError::InvalidHeader)));
+        let data = b"GET / HTTP/1.1\r\n folded: x\r\n\r\n";
+        assert!(matches!(parse_one(data), Err(ParseError::InvalidHeader)));
+
+        let trailer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nT: a\nb\r\n\r\n";
+        assert!(matches!(parse_one(trailer), Err(ParseError::InvalidHeader)));
+        let trailer =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nBad Name: v\r\n\r\n";
+        assert!(matches!(parse_one(trailer), Err(ParseError::InvalidHeader)));
+    }
+
+    #[test]
+    fn request_line_too_long() {
+        let mut parser = Parser::with_limits(small_limits());
+        // caught before the line is even finished
+        parser.feed(b"GET /aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert!(matches!(
+            parser.parse(),
+            Err(ParseError::RequestLineTooLong)
+        ));
+    }
+
+    #[test]
+    fn too_many_headers() {
+        let mut parser = Parser::with_limits(small_limits());
+        parser.feed(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n");
+        assert!(matches!(parser.parse(), Err(ParseError::TooManyHeaders)));
+    }
+
+    #[test]
+    fn headers_too_large() {
+        let mut parser = Parser::with_limits(small_limits());
+        parser.feed(b"GET / HTTP/1.1\r\nA: ");
+        parser.feed(&[b'x'; 64]);
+        assert!(matches!(parser.parse(), Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn body_too_large() {
+        let mut parser = Parser::with_limits(small_limits());
+        // rejected on the header alone, before any of the body arrives
+        parser.feed(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n");
+        assert!(matches!(parser.parse(), Err(ParseError::BodyTooLarge)));
+
+        let mut parser = Parser::with_limits(small_limits());
+        parser.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\naaaaa\r\n4\r\n");
+        assert!(matches!(parser.parse(), Err(ParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn within_limits() {
+        let mut parser = Parser::with_limits(small_limits());
+        parser.feed(b"POST / HTTP/1.1\r\nA: 1\r\nContent-Length: 8\r\n\r\n12345678");
+        assert_eq!(parser.parse().unwrap().unwrap().body(), b"12345678");
+    }
+
+    #[test]
+    fn malformed_request_lines() {
+        assert!(matches!(
+            parse_one(b"GET /\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse_one(b"GET  / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse_one(b"G(T / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidMethod)
+        ));
+        assert!(matches!(
+            parse_one(b"GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::UnsupportedVersion)
+        ));
+    }
+
+    #[test]
+    fn request_line_of_a_failed_request() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.request_line(), None);
+        parser.feed(b"GET /a HT");
+        assert_eq!(parser.request_line().as_deref(), Some("GET /a HT"));
+
+        let mut parser = Parser::with_limits(small_limits());
+        parser.feed(b"POST /big HTTP/1.1\r\nContent-Length: 99\r\n\r\n");
+        assert!(parser.parse().is_err());
+        assert_eq!(parser.request_line().as_deref(), Some("POST /big HTTP/1.1"));
+
+        let mut parser = Parser::new();
+        parser.feed(b"POST /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n");
+        assert!(parser.parse().unwrap().is_none());
+        assert_eq!(parser.request_line().as_deref(), Some("POST /c HTTP/1.1"));
+    }
+
+    #[test]
+    fn read_request_eof() {
+        let mut parser = Parser::new();
+        let mut empty: &[u8] = b"";
+        assert!(parser.read_request(&mut empty).unwrap().is_none());
+        let mut partial: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(matches!(
+            parser.read_request(&mut partial),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+}
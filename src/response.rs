@@ -0,0 +1,381 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::time::SystemTime;
+
+use crate::chunked::ChunkedWriter;
+use crate::date;
+use crate::headers::Headers;
+use crate::request::{Method, Version};
+
+/// Value sent in the `Server` header unless a response sets its own.
+pub const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    Continue,
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode {
+    pub fn as_u16(self) -> u16 {
+        match self {
+            StatusCode::Continue => 100,
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::NotAcceptable => 406,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::Conflict => 409,
+            StatusCode::Gone => 410,
+            StatusCode::LengthRequired => 411,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UriTooLong => 414,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::ExpectationFailed => 417,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason_phrase(self) -> &'static str {
+        match self {
+            StatusCode::Continue => "Continue",
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::NotAcceptable => "Not Acceptable",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::ExpectationFailed => "Expectation Failed",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    /// 1xx, 204 and 304 responses never carry a body.
+    pub fn allows_body(self) -> bool {
+        let code = self.as_u16();
+        code >= 200 && code != 204 && code != 304
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    Stream(Box<dyn Read + Send>),
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+/// An HTTP response, built up by handlers and then written to the client.
+///
+/// `Content-Length`, `Date` and `Server` are filled in when the response is
+/// written unless they've been set explicitly. Streamed bodies without a
+/// `Content-Length` are sent with `Transfer-Encoding: chunked`.
+pub struct Response {
+    status: StatusCode,
+    headers: Headers,
+    body: Body,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// Shorthand for a response with a plain text body, e.g. for errors.
+    pub fn text(status: StatusCode, text: &str) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(text)
+    }
+
+    pub fn with_header<N, V>(mut self, name: N, value: V) -> Response
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.set_body(body);
+        self
+    }
+
+    /// Streams the body from `reader` as the response is written instead of
+    /// holding it all in memory.
+    ///
+    /// Set `Content-Length` if the size is known up front, otherwise the body
+    /// is sent chunked.
+    pub fn with_stream<R: Read + Send + 'static>(mut self, reader: R) -> Response {
+        self.body = Body::Stream(Box::new(reader));
+        self
+    }
+
+    /// Sends each item produced by `chunks` as it comes, e.g. for output
+    /// that's generated bit by bit. Empty items are skipped.
+    pub fn with_chunks<I>(mut self, chunks: I) -> Response
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.body = Body::Chunks(Box::new(chunks.into_iter()));
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// The buffered body. Empty for streamed responses.
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            Body::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    /// True if the body is produced while writing rather than held in
+    /// memory.
+    pub fn is_streamed(&self) -> bool {
+        !matches!(self.body, Body::Bytes(_))
+    }
+
+    pub fn set_body<B: Into<Vec<u8>>>(&mut self, body: B) {
+        self.body = Body::Bytes(body.into());
+    }
+
+    /// Serializes the status line, headers and body to `w`.
+    pub fn write_to<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        self.write_for_request(w, &Method::Get, Version::Http11)
+            .map(|_| ())
+    }
+
+    /// Like `write_to`, but a streamed body of unknown length is sent as-is
+    /// to 1.0 clients, which don't understand chunked. The connection has to
+    /// be closed afterwards to mark the end.
+    ///
+    /// A `HEAD` request gets the same headers, framing included, but no body.
+    ///
+    /// Returns the number of body bytes sent.
+    pub(crate) fn write_for_request<W: Write>(
+        &mut self,
+        w: &mut W,
+        method: &Method,
+        version: Version,
+    ) -> io::Result<u64> {
+        let has_body = self.status.allows_body();
+        let known_length =
+            self.headers.contains("Content-Length") || self.headers.contains("Transfer-Encoding");
+        let chunked = has_body && self.is_streamed() && !known_length && version == Version::Http11;
+
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        if !self.headers.contains("Date") {
+            head.push_str(&format!("Date: {}\r\n", date::http_date(SystemTime::now())));
+        }
+        if !self.headers.contains("Server") {
+            head.push_str(&format!("Server: {}\r\n", SERVER_NAME));
+        }
+        if has_body && !known_length {
+            if let Body::Bytes(bytes) = &self.body {
+                head.push_str(&format!("Content-Length: {}\r\n", bytes.len()));
+            } else if chunked {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+        }
+        self.headers.write_fields(&mut head);
+        head.push_str("\r\n");
+        w.write_all(head.as_bytes())?;
+
+        let mut sent = 0;
+        if has_body && method != &Method::Head {
+            let body = mem::replace(&mut self.body, Body::Bytes(Vec::new()));
+            if chunked {
+                let mut chunked = ChunkedWriter::new(&mut *w);
+                sent = write_body(body, &mut chunked)?;
+                chunked.finish()?;
+            } else {
+                sent = write_body(body, w)?;
+            }
+        }
+        w.flush()?;
+        Ok(sent)
+    }
+}
+
+fn write_body<W: Write>(body: Body, w: &mut W) -> io::Result<u64> {
+    match body {
+        Body::Bytes(bytes) => w.write_all(&bytes).map(|_| bytes.len() as u64),
+        Body::Stream(mut reader) => io::copy(&mut reader, w),
+        Body::Chunks(chunks) => {
+            let mut sent = 0;
+            for chunk in chunks {
+                w.write_all(&chunk)?;
+                // get each piece out to the client as soon as it's made
+                w.flush()?;
+                sent += chunk.len() as u64;
+            }
+            Ok(sent)
+        }
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let body: &dyn fmt::Debug = match &self.body {
+            Body::Bytes(bytes) => bytes,
+            _ => &"<streamed>",
+        };
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", body)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(response: &mut Response, method: Method) -> String {
+        let mut out = Vec::new();
+        response
+            .write_for_request(&mut out, &method, Version::Http11)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn drops_headers_that_would_inject_lines() {
+        let mut response = Response::text(StatusCode::Found, "moved")
+            .with_header("Location", "/next\r\nSet-Cookie: evil=1")
+            .with_header("X-Bad\r\nName", "v")
+            .with_header("X-Nul", "a\0b")
+            .with_header("X-Fine", "ok");
+        let out = written(&mut response, Method::Get);
+        assert!(!out.contains("evil"));
+        assert!(!out.contains("Location"));
+        assert!(!out.contains("X-Bad"));
+        assert!(!out.contains("X-Nul"));
+        assert!(out.contains("\r\nX-Fine: ok\r\n"));
+        assert!(out.ends_with("\r\n\r\nmoved"));
+    }
+
+    #[test]
+    fn drops_bad_trailers() {
+        let mut trailers = Headers::new();
+        trailers.insert("Checksum", "abc\nX: y");
+        trailers.insert("Done", "yes");
+        let mut out = Vec::new();
+        ChunkedWriter::new(&mut out)
+            .finish_with_trailers(&trailers)
+            .unwrap();
+        assert_eq!(out, b"0\r\nDone: yes\r\n\r\n");
+    }
+}
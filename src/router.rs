@@ -0,0 +1,352 @@
+use crate::request::{percent_decode, Method, Request};
+use crate::response::{Response, StatusCode};
+
+/// Something that can answer a request.
+///
+/// Implemented for any `Fn(&Request) -> Response` closure.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    // matches the rest of the path, must be last
+    Wildcard(String),
+}
+
+/// A parsed route pattern such as `/users/:id` or `/static/*path`.
+#[derive(Debug)]
+struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        assert!(
+            pattern.starts_with('/'),
+            "route pattern `{}` must start with `/`",
+            pattern
+        );
+        let parts: Vec<&str> = pattern[1..].split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(
+                    i == parts.len() - 1,
+                    "wildcard must be the last segment in `{}`",
+                    pattern
+                );
+                let name = if name.is_empty() { "*" } else { name };
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            };
+            segments.push(segment);
+        }
+        Pattern {
+            source: pattern.to_string(),
+            segments,
+        }
+    }
+
+    /// Matches `path` against the pattern, returning the captured params.
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let path = path.strip_prefix('/')?;
+        let mut rest = Some(path);
+        let mut params = Vec::new();
+
+        for segment in &self.segments {
+            if let Segment::Wildcard(name) = segment {
+                params.push((name.clone(), percent_decode(rest.unwrap_or(""))?));
+                return Some(params);
+            }
+            let current = rest?;
+            let (part, remainder) = match current.find('/') {
+                Some(i) => (&current[..i], Some(&current[i + 1..])),
+                None => (current, None),
+            };
+            match segment {
+                Segment::Literal(literal) => {
+                    if percent_decode(part)? != *literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    if part.is_empty() {
+                        return None;
+                    }
+                    params.push((name.clone(), percent_decode(part)?));
+                }
+                Segment::Wildcard(_) => unreachable!(),
+            }
+            rest = remainder;
+        }
+
+        if rest.is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+/// Dispatches requests to handlers by method and path.
+///
+/// Patterns are made of `/` separated segments; `:name` captures a single
+/// segment and a trailing `*name` captures the rest of the path. Captured
+/// values are available from `Request::param`. Routes are tried in the order
+/// they were added.
+///
+/// If a path matches but the method doesn't the router answers
+/// `405 Method Not Allowed` with an `Allow` header. Paths that don't match
+/// anything go to the fallback handler, or get a 404 if there isn't one.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Adds a route.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern doesn't start with `/` or has a wildcard
+    /// anywhere but the end.
+    pub fn route<H: Handler>(mut self, method: Method, pattern: &str, handler: H) -> Router {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(self, pattern: &str, handler: H) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Handler for requests that don't match any route, e.g. static files.
+    pub fn fallback<H: Handler>(mut self, handler: H) -> Router {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Finds the route for `request`, stores its params on the request and
+    /// runs the handler.
+    ///
+    /// `HEAD` requests are served by `GET` routes when there's no explicit
+    /// `HEAD` route. The body is left off when the response is written.
+    pub fn handle(&self, request: &mut Request) -> Response {
+        let mut allowed: Vec<&Method> = Vec::new();
+        let mut head_fallback = None;
+
+        for route in &self.routes {
+            let params = match route.pattern.matches(request.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+            if &route.method == request.method() {
+                request.set_route(&route.pattern.source, params);
+                return route.handler.handle(request);
+            }
+            if route.method == Method::Get
+                && request.method() == &Method::Head
+                && head_fallback.is_none()
+            {
+                head_fallback = Some((route, params));
+            }
+            if !allowed.contains(&&route.method) {
+                allowed.push(&route.method);
+            }
+        }
+
+        if let Some((route, params)) = head_fallback {
+            request.set_route(&route.pattern.source, params);
+            return route.handler.handle(request);
+        }
+
+        if !allowed.is_empty() {
+            if allowed.contains(&&Method::Get) && !allowed.contains(&&Method::Head) {
+                allowed.push(&Method::Head);
+            }
+            let allow: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+            return Response::text(StatusCode::MethodNotAllowed, "Method Not Allowed")
+                .with_header("Allow", allow.join(", "));
+        }
+
+        match &self.fallback {
+            Some(fallback) => fallback.handle(request),
+            None => Response::text(StatusCode::NotFound, "Not Found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Parser;
+
+    fn request(method: &str, target: &str) -> Request {
+        let mut parser = Parser::new();
+        parser.feed(format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, target).as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    // answers with the route's name and whatever it captured
+    fn named(name: &'static str) -> impl Handler {
+        move |request: &Request| {
+            let mut text = name.to_string();
+            for (key, value) in request.params() {
+                text.push_str(&format!(" {}={}", key, value));
+            }
+            Response::text(StatusCode::Ok, &text)
+        }
+    }
+
+    fn body(router: &Router, method: &str, target: &str) -> String {
+        let response = router.handle(&mut request(method, target));
+        String::from_utf8(response.body().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn captures_params() {
+        let router = Router::new()
+            .get("/users/:id", named("user"))
+            .get("/users/:id/posts/:post", named("post"));
+        assert_eq!(body(&router, "GET", "/users/42"), "user id=42");
+        assert_eq!(
+            body(&router, "GET", "/users/a%20b/posts/7?x=1"),
+            "post id=a b post=7"
+        );
+
+        let mut req = request("GET", "/users/42");
+        router.handle(&mut req);
+        assert_eq!(req.param("id"), Some("42"));
+        assert_eq!(req.route(), Some("/users/:id"));
+
+        // a param needs something to capture, and the whole path must match
+        assert_eq!(body(&router, "GET", "/users/"), "Not Found");
+        assert_eq!(body(&router, "GET", "/users/42/extra"), "Not Found");
+    }
+
+    #[test]
+    fn wildcard_takes_the_rest() {
+        let router = Router::new()
+            .get("/static/*path", named("static"))
+            .get("/any/*", named("any"));
+        assert_eq!(
+            body(&router, "GET", "/static/css/site.css"),
+            "static path=css/site.css"
+        );
+        assert_eq!(body(&router, "GET", "/static/"), "static path=");
+        assert_eq!(body(&router, "GET", "/any/a/b"), "any *=a/b");
+        assert_eq!(body(&router, "GET", "/other"), "Not Found");
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/a/*rest/b", named("bad"));
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let router = Router::new()
+            .get("/users/me", named("me"))
+            .get("/users/:id", named("user"));
+        assert_eq!(body(&router, "GET", "/users/me"), "me");
+        assert_eq!(body(&router, "GET", "/users/7"), "user id=7");
+
+        // added the other way round the param route shadows the literal one
+        let router = Router::new()
+            .get("/users/:id", named("user"))
+            .get("/users/me", named("me"));
+        assert_eq!(body(&router, "GET", "/users/me"), "user id=me");
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let router = Router::new()
+            .get("/items", named("list"))
+            .post("/items", named("create"))
+            .delete("/items/:id", named("delete"));
+        let response = router.handle(&mut request("PUT", "/items"));
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers().get("Allow"), Some("GET, POST, HEAD"));
+
+        let response = router.handle(&mut request("GET", "/items/3"));
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers().get("Allow"), Some("DELETE"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = Router::new().get("/page/:n", named("get"));
+        let mut req = request("HEAD", "/page/2");
+        let response = router.handle(&mut req);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.body(), b"get n=2");
+        assert_eq!(req.param("n"), Some("2"));
+
+        // an explicit HEAD route is preferred, wherever it is
+        let router = Router::new().get("/page/:n", named("get")).route(
+            Method::Head,
+            "/page/:n",
+            named("head"),
+        );
+        assert_eq!(body(&router, "HEAD", "/page/2"), "head n=2");
+    }
+
+    #[test]
+    fn unmatched_paths_go_to_the_fallback() {
+        let router = Router::new().get("/", named("home"));
+        let response = router.handle(&mut request("GET", "/missing"));
+        assert_eq!(response.status(), StatusCode::NotFound);
+
+        let router = router.fallback(|request: &Request| {
+            Response::text(StatusCode::Ok, &format!("fallback {}", request.path()))
+        });
+        assert_eq!(body(&router, "GET", "/missing"), "fallback /missing");
+        assert_eq!(body(&router, "POST", "/missing"), "fallback /missing");
+        assert_eq!(body(&router, "GET", "/"), "home");
+        // a path that matches with the wrong method is still a 405
+        let response = router.handle(&mut request("POST", "/"));
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+    }
+}
@@ -0,0 +1,272 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::queue::{Priority, PushError};
+use crate::{lock, FnBox, Job, ThreadPool};
+
+/// Spawns jobs that can borrow from the stack, see `ThreadPool::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<State>,
+    // same variance tricks as std::thread::Scope: 'scope must not shrink and
+    // 'env must not grow
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct State {
+    pending: Mutex<usize>,
+    done: Condvar,
+    // the first panic from a job, rethrown when the scope ends
+    panic: Mutex<Option<Box<dyn Any + Send + 'static>>>,
+}
+
+impl State {
+    fn panicked(&self, payload: Box<dyn Any + Send + 'static>) {
+        lock(&self.panic).get_or_insert(payload);
+    }
+
+    fn finished(&self) {
+        let mut pending = lock(&self.pending);
+        *pending -= 1;
+        if *pending == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut pending = lock(&self.pending);
+        while *pending > 0 {
+            pending = self
+                .done
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Queues `f` on the pool. It may borrow anything that outlives the
+    /// scope.
+    ///
+    /// If the pool won't take it, because it's shut down or the queue is
+    /// full and rejects jobs, `f` runs right here on the calling thread.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *lock(&self.state.pending) += 1;
+        let job = ScopedJob {
+            f: Some(f),
+            state: self.state.clone(),
+        };
+        let job: Box<dyn FnBox + Send + 'scope> = Box::new(move || job.run());
+        // SAFETY: the scope doesn't return until every job has run or been
+        // dropped (ScopedJob tells it either way), so nothing borrowed for
+        // 'scope is used after it ends.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnBox + Send + 'scope>, Job>(job) };
+        match self.pool.shared.queue.push(job, Priority::Normal, None) {
+            Ok(()) => self.pool.shared.grow(),
+            Err(PushError::Full(job)) | Err(PushError::Closed(job)) => job.call_box(),
+        }
+    }
+}
+
+/// A job spawned in a scope, which tells the scope when it's done with,
+/// whether it ran, panicked or was dropped from the queue unrun.
+struct ScopedJob<F> {
+    f: Option<F>,
+    state: Arc<State>,
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(mut self) {
+        let f = self.f.take().unwrap();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            self.state.panicked(payload);
+        }
+    }
+}
+
+impl<F> Drop for ScopedJob<F> {
+    fn drop(&mut self) {
+        // the closure and whatever it borrows must be gone before the
+        // scope can end
+        if let Some(f) = self.f.take() {
+            drop(f);
+            self.state.panicked(Box::new(
+                "scoped job was dropped from the queue without running",
+            ));
+        }
+        self.state.finished();
+    }
+}
+
+/// The body of `ThreadPool::scope`.
+pub(crate) fn scope<'env, F, T>(pool: &ThreadPool, f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        pool,
+        state: Arc::new(State {
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+            panic: Mutex::new(None),
+        }),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+    // Even if `f` panics, jobs it spawned may still be using its borrows,
+    // so they have to finish before we unwind any further.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.state.wait();
+    let result = match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    };
+    if let Some(payload) = lock(&scope.state.panic).take() {
+        panic::resume_unwind(payload);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{QueuePolicy, ThreadPool};
+
+    fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+        match payload.downcast::<&str>() {
+            Ok(s) => s.to_string(),
+            Err(payload) => *payload.downcast::<String>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn borrows_from_the_stack() {
+        let pool = ThreadPool::new(3);
+        let mut chunks = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+        let total = AtomicUsize::new(0);
+        let returned = pool.scope(|s| {
+            for chunk in &mut chunks {
+                let total = &total;
+                s.spawn(move || {
+                    chunk.iter_mut().for_each(|n| *n *= 2);
+                    total.fetch_add(chunk.iter().sum(), Ordering::SeqCst);
+                });
+            }
+            "done"
+        });
+        assert_eq!(returned, "done");
+        assert_eq!(chunks, [[2, 4], [6, 8], [10, 12]]);
+        assert_eq!(total.load(Ordering::SeqCst), 42);
+    }
+
+    #[test]
+    fn nested_spawn() {
+        let pool = ThreadPool::new(2);
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            let count = &count;
+            s.spawn(move || {
+                s.spawn(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn job_panic_propagates_after_the_rest_finish() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| panic!("job failed"));
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    finished.store(true, Ordering::SeqCst);
+                });
+            })
+        }));
+        assert_eq!(panic_message(result.unwrap_err()), "job failed");
+        assert!(finished.load(Ordering::SeqCst));
+        // the pool is still fine afterwards
+        assert_eq!(pool.scope(|_| 1), 1);
+    }
+
+    #[test]
+    fn body_panic_waits_for_jobs() {
+        let pool = ThreadPool::new(1);
+        let finished = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    finished.store(true, Ordering::SeqCst);
+                });
+                panic!("body failed");
+            })
+        }));
+        assert_eq!(panic_message(result.unwrap_err()), "body failed");
+        assert!(finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn runs_inline_when_rejected() {
+        let pool = ThreadPool::bounded(1, 1, QueuePolicy::Reject);
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..20 {
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(1));
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn evicted_job_is_reported() {
+        let pool = ThreadPool::bounded(1, 1, QueuePolicy::DropOldest);
+        // hold the only worker so scoped jobs have to queue
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        })
+        .unwrap();
+        started.recv().unwrap();
+
+        let evicted_ran = AtomicBool::new(false);
+        let kept_ran = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|| evicted_ran.store(true, Ordering::SeqCst));
+                // pushes the first one out of the full queue
+                s.spawn(|| kept_ran.store(true, Ordering::SeqCst));
+                release.send(()).unwrap();
+            })
+        }));
+        assert_eq!(
+            panic_message(result.unwrap_err()),
+            "scoped job was dropped from the queue without running"
+        );
+        assert!(!evicted_ran.load(Ordering::SeqCst));
+        assert!(kept_ran.load(Ordering::SeqCst));
+    }
+}
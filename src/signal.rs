@@ -0,0 +1,63 @@
+//! Minimal SIGINT/SIGTERM handling so the server can shut down cleanly.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// True once SIGINT or SIGTERM has been received (or `request_shutdown`
+/// was called).
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Flags a shutdown as if a signal had arrived.
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Installs handlers for SIGINT and SIGTERM that just set the shutdown flag.
+///
+/// On non-unix platforms this does nothing and the process is killed as
+/// usual.
+pub fn install_shutdown_handler() -> io::Result<()> {
+    imp::install()
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_ERR: usize = !0;
+
+    extern "C" {
+        // std already links against libc, so no need for the libc crate.
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    extern "C" fn on_signal(_: c_int) {
+        // only async-signal-safe work in here, an atomic store is fine.
+        super::request_shutdown();
+    }
+
+    pub fn install() -> io::Result<()> {
+        for &sig in &[SIGINT, SIGTERM] {
+            if unsafe { signal(sig, on_signal) } == SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+
+    pub fn install() -> io::Result<()> {
+        Ok(())
+    }
+}
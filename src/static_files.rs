@@ -0,0 +1,267 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::request::{percent_decode, Method, Request};
+use crate::response::{Response, StatusCode};
+
+/// Serves files from a directory on disk.
+///
+/// Request paths are percent-decoded and mapped onto the root. Anything that
+/// would end up outside of it, whether through `..`, an encoded slash or a
+/// symlink, gets a 403.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: String,
+}
+
+impl StaticFiles {
+    /// Serve files under `root`, which must exist.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<StaticFiles> {
+        Ok(StaticFiles {
+            root: root.as_ref().canonicalize()?,
+            index: "index.html".to_string(),
+        })
+    }
+
+    /// File served when a directory is requested, `index.html` by default.
+    pub fn with_index<S: Into<String>>(mut self, index: S) -> StaticFiles {
+        self.index = index.into();
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        match request.method() {
+            Method::Get | Method::Head => {}
+            _ => {
+                return Response::text(StatusCode::MethodNotAllowed, "Method Not Allowed")
+                    .with_header("Allow", "GET, HEAD")
+            }
+        }
+
+        let path = match self.resolve(request.path()) {
+            Ok(path) => path,
+            Err(status) => return Response::text(status, status.reason_phrase()),
+        };
+        let (file, length) = match File::open(&path).and_then(|f| {
+            let length = f.metadata()?.len();
+            Ok((f, length))
+        }) {
+            Ok(opened) => opened,
+            Err(e) => {
+                let status = status_for(&e);
+                return Response::text(status, status.reason_phrase());
+            }
+        };
+
+        let response = Response::new(StatusCode::Ok)
+            .with_header("Content-Type", mime_type(&path))
+            .with_header("Content-Length", length.to_string());
+        if request.method() == &Method::Head {
+            response
+        } else {
+            // stream it rather than pulling large files into memory
+            response.with_stream(file.take(length))
+        }
+    }
+
+    /// Maps a request path onto a file under the root.
+    fn resolve(&self, request_path: &str) -> Result<PathBuf, StatusCode> {
+        if !request_path.starts_with('/') {
+            return Err(StatusCode::BadRequest);
+        }
+
+        let mut path = self.root.clone();
+        for segment in request_path.split('/') {
+            let segment = percent_decode(segment).ok_or(StatusCode::BadRequest)?;
+            match segment.as_str() {
+                "" | "." => continue,
+                ".." => return Err(StatusCode::Forbidden),
+                // an encoded slash (or anything a filesystem might treat as
+                // a separator or prefix) could be used to step outside a
+                // single path segment.
+                s if s.contains(&['/', '\\', ':', '\0'][..]) => return Err(StatusCode::Forbidden),
+                s => path.push(s),
+            }
+        }
+
+        // Resolve symlinks and make sure we're still inside the root.
+        let mut path = path.canonicalize().map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+            _ => StatusCode::NotFound,
+        })?;
+        if !path.starts_with(&self.root) {
+            return Err(StatusCode::Forbidden);
+        }
+        if path.is_dir() {
+            path = path
+                .join(&self.index)
+                .canonicalize()
+                .map_err(|_| StatusCode::Forbidden)?;
+            if !path.starts_with(&self.root) {
+                return Err(StatusCode::Forbidden);
+            }
+        }
+        Ok(path)
+    }
+}
+
+fn status_for(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NotFound,
+        io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+/// Guesses a `Content-Type` from the file extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // A scratch directory with a document root inside it and a secret file
+    // next to the root, removed again on drop.
+    struct Site {
+        dir: PathBuf,
+        files: StaticFiles,
+    }
+
+    impl Site {
+        fn new(name: &str) -> Site {
+            let dir = env::temp_dir().join(format!("rs-webserver-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("root/docs")).unwrap();
+            fs::write(dir.join("secret.txt"), "secret").unwrap();
+            fs::write(dir.join("root/index.html"), "home").unwrap();
+            fs::write(dir.join("root/docs/page.html"), "page").unwrap();
+            let files = StaticFiles::new(dir.join("root")).unwrap();
+            Site { dir, files }
+        }
+
+        fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+            self.files.resolve(path)
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn serves_files_under_the_root() {
+        let site = Site::new("static-ok");
+        let root = site.files.root();
+        assert_eq!(site.resolve("/"), Ok(root.join("index.html")));
+        assert_eq!(
+            site.resolve("/docs/page.html"),
+            Ok(root.join("docs/page.html"))
+        );
+        assert_eq!(
+            site.resolve("/docs/%70age.html"),
+            Ok(root.join("docs/page.html"))
+        );
+        assert_eq!(
+            site.resolve("/./docs//page.html"),
+            Ok(root.join("docs/page.html"))
+        );
+        assert_eq!(site.resolve("/missing"), Err(StatusCode::NotFound));
+        // no index in there
+        assert_eq!(site.resolve("/docs/"), Err(StatusCode::Forbidden));
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let site = Site::new("static-traversal");
+        for path in &[
+            "/../secret.txt",
+            "/docs/../../secret.txt",
+            "/docs/../index.html",
+            "/%2e%2e/secret.txt",
+            "/%2E%2E/secret.txt",
+            "/docs%2f..%2f..%2fsecret.txt",
+            "/%2fetc/passwd",
+            "/..\\secret.txt",
+            "/docs%5c..%5c..%5csecret.txt",
+            "/index.html%00.png",
+            "/c:/secret.txt",
+        ] {
+            assert_eq!(site.resolve(path), Err(StatusCode::Forbidden), "{}", path);
+        }
+        assert_eq!(site.resolve("/%zz"), Err(StatusCode::BadRequest));
+        assert_eq!(site.resolve("secret.txt"), Err(StatusCode::BadRequest));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_out_of_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let site = Site::new("static-symlink");
+        let root = site.files.root().to_path_buf();
+        symlink(site.dir.join("secret.txt"), root.join("link.txt")).unwrap();
+        symlink(&site.dir, root.join("outside")).unwrap();
+        fs::create_dir(root.join("trap")).unwrap();
+        symlink(site.dir.join("secret.txt"), root.join("trap/index.html")).unwrap();
+        // links that stay inside are fine
+        symlink(root.join("docs/page.html"), root.join("inside.html")).unwrap();
+
+        assert_eq!(site.resolve("/link.txt"), Err(StatusCode::Forbidden));
+        assert_eq!(
+            site.resolve("/outside/secret.txt"),
+            Err(StatusCode::Forbidden)
+        );
+        assert_eq!(site.resolve("/outside/"), Err(StatusCode::Forbidden));
+        assert_eq!(site.resolve("/trap/"), Err(StatusCode::Forbidden));
+        assert_eq!(
+            site.resolve("/inside.html"),
+            Ok(root.join("docs/page.html"))
+        );
+    }
+}
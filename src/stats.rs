@@ -0,0 +1,349 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+// Bucket upper bounds in microseconds, roughly 1-2.5-5 steps from 10µs to
+// 10s. Anything slower lands in the overflow bucket.
+const BOUNDS_US: [u64; 19] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+    500_000, 1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+const BUCKETS: usize = BOUNDS_US.len() + 1;
+
+/// A distribution of durations, bucketed on a fixed log scale.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // per bucket, not cumulative; the last one is the overflow
+    counts: [u64; BUCKETS],
+    count: u64,
+    sum_us: u64,
+}
+
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us)
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(Duration::from_micros(self.sum_us / self.count))
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile (0.0 to 1.0),
+    /// e.g. `quantile(0.99)` for p99. `None` if nothing was recorded or it's
+    /// in the overflow bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return BOUNDS_US.get(i).map(|&us| Duration::from_micros(us));
+            }
+        }
+        None
+    }
+
+    /// Cumulative counts as `(upper bound, samples at or below it)`, with
+    /// `None` standing for infinity on the last one.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        let mut total = 0;
+        self.counts.iter().enumerate().map(move |(i, n)| {
+            total += n;
+            (BOUNDS_US.get(i).map(|&us| Duration::from_micros(us)), total)
+        })
+    }
+
+    pub(crate) fn record(&mut self, d: Duration) {
+        let us = micros(d);
+        self.counts[bucket(us)] += 1;
+        self.count += 1;
+        self.sum_us += us;
+    }
+
+    /// Adds `other`'s samples to this one.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.count += other.count;
+        self.sum_us += other.sum_us;
+    }
+}
+
+/// The lock-free side of a `Histogram`, recorded into by workers.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    counts: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Recorder {
+    pub fn record(&self, d: Duration) {
+        let us = micros(d);
+        self.counts[bucket(us)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Histogram {
+        let mut counts = [0; BUCKETS];
+        for (n, c) in counts.iter_mut().zip(self.counts.iter()) {
+            *n = c.load(Ordering::Relaxed);
+        }
+        Histogram {
+            counts,
+            // recomputed so the buckets always add up, even if we raced a
+            // record()
+            count: counts.iter().sum(),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros().min(u64::MAX as u128) as u64
+}
+
+fn bucket(us: u64) -> usize {
+    BOUNDS_US
+        .iter()
+        .position(|&bound| us <= bound)
+        .unwrap_or(BUCKETS - 1)
+}
+
+/// Counters kept by a worker, or for the pool as a whole.
+#[derive(Default)]
+pub(crate) struct Activity {
+    pub busy: AtomicBool,
+    pub completed: AtomicU64,
+    pub panicked: AtomicU64,
+    pub wait: Recorder,
+    pub run: Recorder,
+}
+
+impl Activity {
+    /// Notes a finished job that sat in the queue for `waited` and then ran
+    /// for `ran`.
+    pub fn job_done(&self, waited: Duration, ran: Duration, panicked: bool) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        if panicked {
+            self.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wait.record(waited);
+        self.run.record(ran);
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy.load(Ordering::SeqCst)
+    }
+}
+
+/// A snapshot of one worker, from `PoolStats::workers`.
+///
+/// Counts start from when the worker thread started; a worker replaced after
+/// a crash starts again from zero.
+#[derive(Clone, Debug)]
+pub struct WorkerStats {
+    id: usize,
+    busy: bool,
+    completed: u64,
+    panicked: u64,
+    wait: Histogram,
+    run: Histogram,
+}
+
+impl WorkerStats {
+    pub(crate) fn new(id: usize, activity: &Activity) -> WorkerStats {
+        WorkerStats {
+            id,
+            busy: activity.is_busy(),
+            completed: activity.completed.load(Ordering::Relaxed),
+            panicked: activity.panicked.load(Ordering::Relaxed),
+            wait: activity.wait.snapshot(),
+            run: activity.run.snapshot(),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Jobs finished, including ones that panicked.
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    pub fn panicked(&self) -> u64 {
+        self.panicked
+    }
+
+    /// How long the jobs this worker ran had waited in the queue.
+    pub fn queue_wait(&self) -> &Histogram {
+        &self.wait
+    }
+
+    /// How long the jobs took to run.
+    pub fn run_time(&self) -> &Histogram {
+        &self.run
+    }
+}
+
+/// A point in time snapshot of a `ThreadPool`, from `ThreadPool::stats`.
+///
+/// The totals cover the pool's whole life, including workers that have
+/// since been retired or replaced.
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    pub(crate) queued: usize,
+    pub(crate) workers: Vec<WorkerStats>,
+    pub(crate) completed: u64,
+    pub(crate) panicked: u64,
+    pub(crate) cancelled: u64,
+    pub(crate) wait: Histogram,
+    pub(crate) run: Histogram,
+}
+
+impl PoolStats {
+    /// Jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    pub fn workers(&self) -> &[WorkerStats] {
+        &self.workers
+    }
+
+    pub fn busy_workers(&self) -> usize {
+        self.workers.iter().filter(|w| w.busy).count()
+    }
+
+    pub fn idle_workers(&self) -> usize {
+        self.workers.len() - self.busy_workers()
+    }
+
+    /// Jobs finished, including ones that panicked.
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    pub fn panicked(&self) -> u64 {
+        self.panicked
+    }
+
+    /// Jobs dropped from the queue because their token was cancelled
+    /// before they started.
+    pub fn cancelled(&self) -> u64 {
+        self.cancelled
+    }
+
+    /// Time jobs spent in the queue before a worker picked them up.
+    pub fn queue_wait(&self) -> &Histogram {
+        &self.wait
+    }
+
+    /// Time jobs took to run.
+    pub fn run_time(&self) -> &Histogram {
+        &self.run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn us(n: u64) -> Duration {
+        Duration::from_micros(n)
+    }
+
+    fn histogram(samples: &[u64]) -> Histogram {
+        let mut h = Histogram::default();
+        for &n in samples {
+            h.record(us(n));
+        }
+        h
+    }
+
+    #[test]
+    fn bounds_are_inclusive() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(10), 0);
+        assert_eq!(bucket(11), 1);
+        assert_eq!(bucket(25), 1);
+        assert_eq!(bucket(1_000_000), 15);
+        assert_eq!(bucket(10_000_000), 18);
+        assert_eq!(bucket(10_000_001), 19);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        // sub-microsecond durations round down, huge ones saturate
+        assert_eq!(micros(Duration::from_nanos(999)), 0);
+        assert_eq!(micros(Duration::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let h = histogram(&[5, 20, 20, 3_000_000, 60_000_000]);
+        let buckets: Vec<_> = h.buckets().collect();
+        assert_eq!(buckets.len(), BUCKETS);
+        assert_eq!(buckets[0], (Some(us(10)), 1));
+        assert_eq!(buckets[1], (Some(us(25)), 3));
+        assert_eq!(buckets[2], (Some(us(50)), 3));
+        assert_eq!(buckets[16], (Some(us(2_500_000)), 3));
+        assert_eq!(buckets[17], (Some(us(5_000_000)), 4));
+        assert_eq!(buckets[18], (Some(us(10_000_000)), 4));
+        assert_eq!(buckets[19], (None, 5));
+        assert_eq!(h.count(), 5);
+        assert_eq!(h.sum(), us(63_000_045));
+        assert_eq!(h.mean(), Some(us(12_600_009)));
+    }
+
+    #[test]
+    fn quantiles_are_bucket_bounds() {
+        assert_eq!(Histogram::default().quantile(0.5), None);
+        assert_eq!(Histogram::default().mean(), None);
+
+        // 100 samples: 50 at 8µs, 40 at 80µs, 9 at 2ms and one at 20s
+        let mut samples = vec![8; 50];
+        samples.extend(vec![80; 40]);
+        samples.extend(vec![2_000; 9]);
+        samples.push(20_000_000);
+        let h = histogram(&samples);
+        assert_eq!(h.quantile(0.0), Some(us(10)));
+        assert_eq!(h.quantile(0.5), Some(us(10)));
+        assert_eq!(h.quantile(0.51), Some(us(100)));
+        assert_eq!(h.quantile(0.9), Some(us(100)));
+        assert_eq!(h.quantile(0.95), Some(us(2_500)));
+        assert_eq!(h.quantile(0.99), Some(us(2_500)));
+        // the slowest is past the last bound
+        assert_eq!(h.quantile(1.0), None);
+        // out of range is clamped
+        assert_eq!(h.quantile(-1.0), h.quantile(0.0));
+        assert_eq!(h.quantile(2.0), h.quantile(1.0));
+    }
+
+    #[test]
+    fn merge_and_snapshot_agree() {
+        let recorder = Recorder::default();
+        for n in [5, 300, 300, 70_000] {
+            recorder.record(us(n));
+        }
+        let snapshot = recorder.snapshot();
+        assert_eq!(snapshot, histogram(&[5, 300, 300, 70_000]));
+
+        let mut merged = histogram(&[5, 300]);
+        merged.merge(&histogram(&[300, 70_000]));
+        assert_eq!(merged, snapshot);
+    }
+}
@@ -0,0 +1,383 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::queue::{Priority, PushError};
+use crate::{lock, ExecuteError, Job, Shared};
+
+/// A job queued with `ThreadPool::schedule_after` or `schedule_every`.
+///
+/// Dropping the handle leaves the job scheduled, call `cancel` to stop it.
+#[derive(Clone, Debug)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    /// Stops the job from running again. A run that has already been handed
+    /// to a worker still goes ahead.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) enum Task {
+    Once(Job),
+    Every {
+        interval: Duration,
+        f: Arc<dyn Fn() + Send + Sync>,
+        // set while a run is queued or going, so a slow job doesn't pile up
+        running: Arc<AtomicBool>,
+    },
+}
+
+struct Entry {
+    due: Instant,
+    // keeps jobs due at the same time in the order they were scheduled
+    seq: u64,
+    task: Task,
+    cancelled: Arc<AtomicBool>,
+}
+
+// BinaryHeap is a max-heap, so the order is flipped to get the earliest
+// entry on top.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> CmpOrdering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+/// Holds scheduled jobs until they're due and then hands them to the
+/// workers. Its thread is only started once something is scheduled.
+pub(crate) struct Timer {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    // cancelled entries stay in the heap until they come up; once it grows
+    // past this they're cleared out
+    purge_at: usize,
+    stopped: bool,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            state: Mutex::new(State {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                purge_at: 64,
+                stopped: false,
+                thread: None,
+            }),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Schedules `task` to be queued at `due`. Fails once the timer has
+    /// been stopped, or if its thread can't be started.
+    pub fn add(
+        &self,
+        shared: &Arc<Shared>,
+        due: Instant,
+        task: Task,
+    ) -> Result<TimerHandle, ExecuteError> {
+        let mut state = lock(&self.state);
+        if state.stopped {
+            return Err(ExecuteError::ShutDown);
+        }
+        if state.thread.is_none() {
+            let shared = shared.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("{}-timer", shared.threads.name_prefix))
+                .spawn(move || run(&shared));
+            match spawned {
+                Ok(thread) => state.thread = Some(thread),
+                // the next call tries again
+                Err(e) => {
+                    crate::error!(error = e; "couldn't start the timer thread");
+                    return Err(ExecuteError::NoTimer);
+                }
+            }
+        }
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.entries.push(Entry {
+            due,
+            seq,
+            task,
+            cancelled: cancelled.clone(),
+        });
+        if state.entries.len() >= state.purge_at {
+            state
+                .entries
+                .retain(|entry| !entry.cancelled.load(Ordering::SeqCst));
+            state.purge_at = (state.entries.len() * 2).max(64);
+        }
+        // it may be due before whatever the thread is waiting on
+        self.wake.notify_one();
+        Ok(TimerHandle { cancelled })
+    }
+
+    /// Drops everything still scheduled and tells the thread to stop. The
+    /// thread is handed back to be joined once the queue is closed, in case
+    /// it's blocked pushing a job.
+    pub fn stop(&self) -> Option<thread::JoinHandle<()>> {
+        let mut state = lock(&self.state);
+        state.stopped = true;
+        state.entries.clear();
+        self.wake.notify_one();
+        state.thread.take()
+    }
+}
+
+/// `start + delay`, or as far off as makes no difference if that's past what
+/// an `Instant` can hold.
+pub(crate) fn after(start: Instant, delay: Duration) -> Instant {
+    // a century is representable everywhere and still never comes
+    const FOREVER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+    start.checked_add(delay).unwrap_or_else(|| start + FOREVER)
+}
+
+fn run(shared: &Arc<Shared>) {
+    let timer = &shared.timer;
+    let mut state = lock(&timer.state);
+    loop {
+        if state.stopped {
+            return;
+        }
+        let now = Instant::now();
+        let due = state.entries.peek().map(|entry| entry.due);
+        match due {
+            None => {
+                state = timer
+                    .wake
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            Some(due) if due > now => {
+                state = timer
+                    .wake
+                    .wait_timeout(state, due - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+                continue;
+            }
+            Some(_) => {}
+        }
+
+        let entry = state.entries.pop().unwrap();
+        if entry.cancelled.load(Ordering::SeqCst) {
+            continue;
+        }
+        let job: Job = match entry.task {
+            Task::Once(job) => job,
+            Task::Every {
+                interval,
+                f,
+                running,
+            } => {
+                let skip = running.swap(true, Ordering::SeqCst);
+                // ticks missed while we were busy are skipped, not run in a
+                // burst to catch up
+                let mut next = after(entry.due, interval);
+                if next <= now {
+                    next = after(now, interval);
+                }
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.entries.push(Entry {
+                    due: next,
+                    seq,
+                    task: Task::Every {
+                        interval,
+                        f: f.clone(),
+                        running: running.clone(),
+                    },
+                    cancelled: entry.cancelled,
+                });
+                if skip {
+                    crate::debug!("periodic job still running, skipping a tick");
+                    continue;
+                }
+                let running = Running(running);
+                Box::new(move || {
+                    let _running = running;
+                    f();
+                })
+            }
+        };
+
+        drop(state);
+        match shared.queue.push(job, Priority::Normal, None) {
+            Ok(()) => shared.grow(),
+            Err(PushError::Full(_)) => crate::warn!("job queue is full, dropped a scheduled job"),
+            Err(PushError::Closed(_)) => {}
+        }
+        state = lock(&timer.state);
+    }
+}
+
+// Clears a periodic job's running flag when its run ends, panics and runs
+// that never happen (the job was dropped from a full queue) included.
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    // long enough that a loaded machine shouldn't make the tests flaky
+    const TICK: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn runs_once_after_the_delay() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        pool.schedule_after(TICK * 2, move || tx.send(Instant::now()).unwrap())
+            .unwrap();
+        let ran = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ran - start >= TICK * 2);
+        // and only once
+        assert!(rx.recv_timeout(TICK * 5).is_err());
+    }
+
+    #[test]
+    fn due_jobs_run_in_order() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        for (n, delay) in [(3, 6), (1, 2), (2, 4)] {
+            let tx = tx.clone();
+            pool.schedule_after(TICK * delay, move || tx.send(n).unwrap())
+                .unwrap();
+        }
+        let ran: Vec<_> = (0..3)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(ran, [1, 2, 3]);
+    }
+
+    #[test]
+    fn repeats_until_cancelled() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let handle = pool
+            .schedule_every(TICK, move || lock(&tx).send(()).unwrap())
+            .unwrap();
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        handle.cancel();
+        assert!(handle.is_cancelled());
+        // one run may already have been handed to the worker
+        thread::sleep(TICK * 3);
+        while rx.try_recv().is_ok() {}
+        assert!(rx.recv_timeout(TICK * 5).is_err());
+    }
+
+    #[test]
+    fn cancelled_before_it_is_due() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = ran.clone();
+        let handle = pool
+            .schedule_after(TICK, move || flag.store(true, Ordering::SeqCst))
+            .unwrap();
+        handle.cancel();
+        thread::sleep(TICK * 5);
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn skips_ticks_while_still_running() {
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let (runs, overlapped) = (runs.clone(), overlapped.clone());
+            pool.schedule_every(TICK, move || {
+                if running.swap(true, Ordering::SeqCst) {
+                    overlapped.store(true, Ordering::SeqCst);
+                }
+                runs.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(TICK * 4);
+                running.store(false, Ordering::SeqCst);
+            })
+            .unwrap()
+        };
+        thread::sleep(TICK * 20);
+        handle.cancel();
+        assert!(!overlapped.load(Ordering::SeqCst));
+        // a run every tick would be 20, skipping it's at most one in four
+        let runs = runs.load(Ordering::SeqCst);
+        assert!((1..=6).contains(&runs), "{} runs", runs);
+    }
+
+    #[test]
+    fn endless_delays_are_accepted() {
+        let pool = ThreadPool::new(1);
+        let once = pool.schedule_after(Duration::MAX, || {}).unwrap();
+        let every = pool.schedule_every(Duration::MAX, || {}).unwrap();
+        once.cancel();
+        every.cancel();
+        assert!(pool.shutdown(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn nothing_is_scheduled_after_shutdown() {
+        let pool = ThreadPool::new(1);
+        pool.schedule_after(Duration::from_secs(3600), || {})
+            .unwrap();
+        assert!(pool.shutdown(Duration::from_secs(5)).is_ok());
+        assert_eq!(
+            pool.schedule_after(TICK, || {}).unwrap_err(),
+            ExecuteError::ShutDown
+        );
+    }
+
+    #[test]
+    fn after_saturates() {
+        let now = Instant::now();
+        assert_eq!(after(now, TICK), now + TICK);
+        assert!(after(now, Duration::MAX) > now + Duration::from_secs(365 * 24 * 60 * 60));
+    }
+}
@@ -0,0 +1,199 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::queue::Pop;
+use crate::stats::{Activity, WorkerStats};
+use crate::{Hook, Shared};
+
+/// Details of a job that panicked, passed to the pool's panic handler.
+pub struct JobPanic {
+    worker: usize,
+    payload: Box<dyn Any + Send + 'static>,
+}
+
+impl JobPanic {
+    /// Id of the worker the job was running on.
+    pub fn worker_id(&self) -> usize {
+        self.worker
+    }
+
+    /// The panic message, if the job panicked with a string.
+    pub fn message(&self) -> Option<&str> {
+        if let Some(s) = self.payload.downcast_ref::<&str>() {
+            Some(s)
+        } else {
+            self.payload.downcast_ref::<String>().map(|s| s.as_str())
+        }
+    }
+
+    pub fn payload(&self) -> &(dyn Any + Send + 'static) {
+        &*self.payload
+    }
+}
+
+impl fmt::Debug for JobPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobPanic")
+            .field("worker", &self.worker)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+pub struct Worker {
+    pub(crate) id: usize,
+    pub(crate) thread: Option<thread::JoinHandle<()>>,
+    activity: Arc<Activity>,
+}
+
+impl Worker {
+    pub(crate) fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let activity = Arc::new(Activity::default());
+        let counters = activity.clone();
+        let mut builder =
+            thread::Builder::new().name(format!("{}-{}", shared.threads.name_prefix, id));
+        if let Some(size) = shared.threads.stack_size {
+            builder = builder.stack_size(size);
+        }
+        shared.worker_started();
+        // taken before the thread starts, so a set_bounds that wakes
+        // everyone before it gets going isn't missed
+        let seen = shared.queue.generation();
+        let registry = shared.clone();
+        let spawned = builder.spawn(move || {
+            // If anything gets past catch_unwind (say the panic handler itself
+            // panics) the sentinel brings up a replacement as we unwind.
+            let sentinel = Sentinel { id, shared };
+            let shared = &sentinel.shared;
+            run_hook(&shared.threads.on_start, id);
+            run(id, shared, &counters, seen);
+            run_hook(&shared.threads.on_stop, id);
+        });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(e) => {
+                registry.worker_exited();
+                return Err(e);
+            }
+        };
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+            activity,
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// True while the worker is running a job.
+    pub fn is_busy(&self) -> bool {
+        self.activity.is_busy()
+    }
+
+    /// A snapshot of this worker's counters.
+    pub fn stats(&self) -> WorkerStats {
+        WorkerStats::new(self.id, &self.activity)
+    }
+}
+
+/// Takes jobs until the queue closes or the pool doesn't need this worker
+/// any more.
+fn run(id: usize, shared: &Shared, activity: &Activity, mut seen: u64) {
+    // a replacement for one that died may have missed a shrink
+    if shared.retire(id, Duration::ZERO) {
+        crate::debug!(worker = id; "not needed, retiring");
+        return;
+    }
+    let mut idle_since = Instant::now();
+    // the pool only shrinks through set_bounds, which bumps the generation,
+    // so there's no need to take the registry lock after every job
+    loop {
+        let (job, waited) = match shared.queue.pop(id, shared.idle_timeout(), seen) {
+            Pop::Job(job, waited) => (job, waited),
+            Pop::Idle => {
+                seen = shared.queue.generation();
+                if shared.retire(id, idle_since.elapsed()) {
+                    crate::debug!(worker = id; "idle, retiring");
+                    return;
+                }
+                continue;
+            }
+            Pop::Closed => break,
+        };
+        crate::trace!(worker = id; "got a job");
+        activity.busy.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job.call_box()));
+        let ran = started.elapsed();
+        activity.busy.store(false, Ordering::SeqCst);
+        activity.job_done(waited, ran, result.is_err());
+        shared.totals.job_done(waited, ran, result.is_err());
+        crate::trace!(
+            worker = id,
+            duration_us = ran.as_micros();
+            "job finished"
+        );
+        if let Err(payload) = result {
+            shared.job_panicked(JobPanic {
+                worker: id,
+                payload,
+            });
+        }
+        // the pool may have been shrunk while we were busy
+        let generation = shared.queue.generation();
+        if generation != seen {
+            seen = generation;
+            if shared.retire(id, Duration::ZERO) {
+                crate::debug!(worker = id; "pool shrunk, retiring");
+                return;
+            }
+        }
+        idle_since = Instant::now();
+    }
+    crate::debug!(worker = id; "terminating");
+}
+
+fn run_hook(hook: &Option<Hook>, id: usize) {
+    if let Some(hook) = hook {
+        // a broken hook shouldn't take the worker down with it
+        if panic::catch_unwind(AssertUnwindSafe(|| hook(id))).is_err() {
+            crate::error!(worker = id; "thread start/stop hook panicked");
+        }
+    }
+}
+
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let mut workers = self.shared.lock_workers();
+            // one that's already been retired isn't wanted back
+            if let Some(slot) = workers.iter().position(|w| w.id == self.id) {
+                crate::warn!(worker = self.id; "worker died, respawning");
+                match Worker::new(self.id, self.shared.clone()) {
+                    // Our own handle is replaced; the pool joins the new thread.
+                    Ok(worker) => workers[slot] = worker,
+                    Err(e) => {
+                        crate::error!(worker = self.id, error = e; "couldn't respawn worker");
+                        // grow brings the numbers back up once there's work
+                        workers.remove(slot);
+                    }
+                }
+            }
+        }
+        self.shared.worker_exited();
+    }
+}